@@ -0,0 +1,79 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::codegen::{SourceFile, SOURCE_MAP_FILE};
+use crate::encoding::encode;
+use crate::error::{CError, CResult};
+
+/// How a file of the output folder compares to the generated one
+pub enum FileStatus {
+  /// The file is generated but isn't in the output folder
+  Added,
+
+  /// The file in the output folder differs from the generated one
+  Changed,
+
+  /// The file is in the output folder but is no longer generated
+  Stale
+}
+
+/// Compare the generated files to the content of the output folder, then
+/// report every difference and fail if there is any.
+pub fn check_output(files: Vec<(PathBuf, SourceFile)>, out: &Path) -> CResult<()> {
+  let mut differences = Vec::new();
+
+  for (suffix, file) in &files {
+    // the bytes are compared so a file in the wrong encoding is outdated too
+    match std::fs::read(out.join(suffix)) {
+      Err(_) => differences.push((suffix.clone(), FileStatus::Added)),
+      Ok(bytes) if bytes != encode(&file.contents, file.format) => {
+        differences.push((suffix.clone(), FileStatus::Changed))
+      }
+      Ok(_) => {}
+    }
+  }
+
+  let generated: HashSet<&PathBuf> = files.iter().map(|(suffix, _)| suffix).collect();
+  for path in list_files(out)? {
+    let suffix = path.strip_prefix(out).unwrap_or(&path).to_path_buf();
+
+    // the source map isn't compared as it holds the paths to the recipes,
+    // which depend on where the build was made
+    if suffix.as_os_str() == SOURCE_MAP_FILE {
+      continue;
+    }
+
+    if !generated.contains(&suffix) {
+      differences.push((suffix, FileStatus::Stale));
+    }
+  }
+
+  differences.sort_by(|a, b| a.0.cmp(&b.0));
+  crate::cli::prints::check_result(out, &differences);
+
+  match differences.is_empty() {
+    true => Ok(()),
+    false => Err(CError::OutdatedOutput(differences.len()))
+  }
+}
+
+/// Recursively list the files in the folder, a missing folder has no file
+fn list_files(folder: &Path) -> std::io::Result<Vec<PathBuf>> {
+  let mut output = Vec::new();
+
+  let Ok(dir) = std::fs::read_dir(folder) else {
+    return Ok(output);
+  };
+
+  for entry in dir {
+    let path = entry?.path();
+
+    if path.is_dir() {
+      output.append(&mut list_files(&path)?);
+    } else {
+      output.push(path);
+    }
+  }
+
+  Ok(output)
+}
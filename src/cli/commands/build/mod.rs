@@ -1,109 +1,257 @@
+use std::collections::HashMap;
 use std::fs::DirEntry;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::codegen::{ExportDatabase, FilePool, FileSearchBehaviour};
-use crate::encoding::read_file;
+use crate::codegen::{ExportDatabase, FilePool, FileSearchBehaviour, MarkerStyle, OnceMode};
+use crate::encoding::{read_file, TextEncoding};
 use crate::error::CResult;
-use crate::parser::{Context, Directive, DirectiveId};
+use crate::game::paths;
+use crate::game::settings::ModSettings;
+use crate::parser::{Context, Directive, DirectiveId, Origin};
 
 mod watcher;
 pub use watcher::build_and_watch;
 
+mod check;
+pub use check::FileStatus;
+
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
 pub struct BuildOptions {
   pub clean_before_build: bool,
   pub without_mods: bool,
-  pub recipes_dir: Option<PathBuf>
+  pub strict: bool,
+  pub markers: MarkerStyle,
+
+  /// The `once` mode of the directives that don't set their own
+  pub once: Option<OnceMode>,
+
+  /// Apply the directives to all of their files or to none of them
+  pub atomic: bool,
+
+  /// The encoding to write every file in, rather than the one of its base file
+  pub encoding: Option<TextEncoding>,
+
+  /// The mods the game loads and their priorities, from `mods.settings`
+  pub mod_settings: ModSettings,
+  pub recipes_dir: Option<PathBuf>,
+
+  /// Run the whole build but print the changes rather than writing them
+  pub dry_run: bool,
+
+  /// Where to write the changes of a dry run, they're printed if `None`
+  pub patch: Option<PathBuf>,
+
+  /// Run the whole build and compare the result to the existing output rather
+  /// than writing it
+  pub check: bool
 }
 
-pub fn build(game_root: &PathBuf, out: &PathBuf, options: &BuildOptions) -> CResult<()> {
+pub fn build(game_root: &Path, out: &Path, options: &BuildOptions) -> CResult<()> {
   crate::cli::prints::build(out);
 
-  if options.clean_before_build {
-    if out.exists() {
-      crate::cli::prints::clean_files();
-      std::fs::remove_dir_all(&out)?;
-    }
+  // a dry run or a check never touches the disk, the cleaning is simulated by
+  // ignoring the existing output files
+  if options.clean_before_build && !options.dry_run && !options.check && out.exists() {
+    crate::cli::prints::clean_files();
+    std::fs::remove_dir_all(out)?;
   }
 
-  scan_mods(game_root, out, &options)
+  scan_mods(game_root, out, options)
 }
 
-fn scan_mods(game_root: &PathBuf, out: &PathBuf, options: &BuildOptions) -> CResult<()> {
-  use rayon::prelude::*;
-  let (mut directives, mod_names): (Vec<Directive>, Vec<String>) =
-    match options.recipes_dir.as_ref() {
-      // no mod override, scan the "mods" folder deduced from the game_root
-      None => {
-        let mod_names = list_mods(&game_root)
-          .filter_map(|module| module.file_name().to_str().map(str::to_owned))
-          .collect();
-
-        let directives = list_mods(&game_root)
-          .par_bridge()
-          .into_par_iter()
-          // recipes are expected to be in a `cahirp` folder inside the mods
-          .flat_map(|module| parse_dir_recipes(module.path().join("cahirp")))
-          .collect();
-
-        (directives, mod_names)
-      }
-      // an override is provided, scan only this folder for recipes
-      Some(dir) => (
-        vec![dir]
-          .into_par_iter()
-          .flat_map(|module| parse_dir_recipes(module.to_path_buf()))
-          .collect(),
-        // with an override there is no installed mod since there is no mods folder
-        vec![]
-      )
-    };
+fn scan_mods(game_root: &Path, out: &Path, options: &BuildOptions) -> CResult<()> {
+  let (directives, mod_names, _) = collect_directives(game_root, options)?;
+  let file_pool = new_file_pool(directives, game_root, out, options)?;
+  let file_pool = file_pool.emit(
+    out,
+    &mod_names,
+    options.markers,
+    options.once,
+    options.atomic
+  )?;
+
+  if options.check {
+    return check::check_output(file_pool.into_files(out), out);
+  }
 
-  // assigns ids to the directives
-  let mut index = 0;
-  for directive in &mut directives {
-    directive.id = DirectiveId::new(index);
-    index += 1;
+  if !options.dry_run {
+    return Ok(file_pool.persist(out)?);
   }
 
+  let diff = file_pool.diff(out);
+  match &options.patch {
+    Some(patch) => {
+      std::fs::write(patch, diff)?;
+      crate::cli::prints::dry_run_patch(patch);
+    }
+    None => print!("{diff}")
+  };
+
+  Ok(())
+}
+
+/// Parse the recipes of the installed mods, or of the recipes folder if one is
+/// supplied, then assign ids to the directives and resolve their `use`
+/// parameters.
+///
+/// Returns the directives that emit code, the names of the installed mods and
+/// the exported directives.
+pub fn collect_directives(
+  game_root: &Path, options: &BuildOptions
+) -> CResult<(Vec<Directive>, Vec<String>, ExportDatabase)> {
+  let (mut directives, mod_names) = parse_directives(game_root, options);
+
   let export_db = ExportDatabase::collect_named_exports(&mut directives);
-  export_db.feed_exports(&mut directives);
+  export_db.feed_exports(&mut directives, options.strict)?;
+
+  Ok((directives, mod_names, export_db))
+}
 
+/// Parse the recipes of the installed mods, or of the recipes folder if one is
+/// supplied, and assign ids to the directives. Their `use` parameters aren't
+/// resolved yet.
+///
+/// Returns the directives and the names of the installed mods.
+pub fn parse_directives(game_root: &Path, options: &BuildOptions) -> (Vec<Directive>, Vec<String>) {
+  let (mut directives, mod_names) = read_directives(game_root, options);
+  order_directives(&mut directives);
+
+  (directives, mod_names)
+}
+
+/// Parse the recipes, the directives come in any order as they're parsed in
+/// parallel.
+fn read_directives(game_root: &Path, options: &BuildOptions) -> (Vec<Directive>, Vec<String>) {
+  use rayon::prelude::*;
+  match options.recipes_dir.as_ref() {
+    // no mod override, scan the "mods" folder deduced from the game_root
+    None => {
+      let mut mod_names: Vec<String> = list_mods(game_root, &options.mod_settings)
+        .filter_map(|module| module.file_name().to_str().map(str::to_owned))
+        .collect();
+      mod_names.sort();
+
+      let directives = list_mods(game_root, &options.mod_settings)
+        .par_bridge()
+        .into_par_iter()
+        // recipes are expected to be in a `cahirp` folder inside the mods
+        .flat_map(|module| parse_dir_recipes(module.path().join("cahirp")))
+        .collect();
+
+      (directives, mod_names)
+    }
+    // an override is provided, scan only this folder for recipes
+    Some(dir) => (
+      vec![dir]
+        .into_par_iter()
+        .flat_map(|module| parse_dir_recipes(module.to_path_buf()))
+        .collect(),
+      // with an override there is no installed mod since there is no mods folder
+      vec![]
+    )
+  }
+}
+
+/// Sort the directives in the order of the recipes, for the builds to be the
+/// same on every run, then tell apart the directives that were given the same
+/// id.
+fn order_directives(directives: &mut [Directive]) {
+  directives.sort_by(|a, b| a.source_order().cmp(&b.source_order()));
+
+  // two directives of a recipe can be given the same `id`, the second one then
+  // falls back to an id derived from its line
+  let mut ids = HashMap::new();
+  let mut copies: HashMap<DirectiveId, usize> = HashMap::new();
+  for directive in directives.iter_mut() {
+    if let Some(&first_line) = ids.get(&directive.id) {
+      let recipe = directive.origin.recipe_name();
+
+      match directive.parameters().id() {
+        Some(_) => {
+          let line = directive.origin.line.to_string();
+          directive.id = DirectiveId::derive(&[&directive.origin.module, &recipe, &line]);
+
+          crate::cli::prints::directive_id_duplicate(directive, first_line);
+        }
+        // the same directive written more than once in the recipe, the copies
+        // are told apart by their rank rather than by the other directives
+        None => {
+          let copy = copies.entry(directive.id).or_default();
+          *copy += 1;
+
+          let content = directive.content();
+          directive.id = DirectiveId::derive(&[
+            &directive.origin.module,
+            &recipe,
+            &content,
+            &copy.to_string()
+          ]);
+        }
+      }
+    }
+
+    ids.insert(directive.id, directive.origin.line);
+  }
+}
+
+/// Create the [FilePool] the directives will work on, with the base files
+/// picked according to the options.
+pub fn new_file_pool(
+  directives: Vec<Directive>, game_root: &Path, out: &Path, options: &BuildOptions
+) -> CResult<FilePool> {
   let search_behaviour = match options.without_mods {
     true => FileSearchBehaviour::Content0,
     false => FileSearchBehaviour::Content0AndMods
   };
 
-  let file_pool = FilePool::new(directives, export_db, &game_root, &out, search_behaviour)?;
-
-  file_pool.emit(&out, &mod_names)?.persist()?;
-
-  Ok(())
+  let file_pool = FilePool::new(
+    directives,
+    game_root,
+    out,
+    search_behaviour,
+    options.clean_before_build || options.check,
+    &options.mod_settings,
+    options.strict
+  )?;
+
+  Ok(file_pool.force_encoding(options.encoding))
 }
 
 /// List the mods found in the mod directory while handling any eventual error
-/// in the process, yielding only the Ok results.
-fn list_mods(game_root: &PathBuf) -> impl Iterator<Item = DirEntry> {
-  let mods_folder = game_root.join("mods");
-  let Ok(mods) = std::fs::read_dir(mods_folder) else {
+/// in the process, yielding only the Ok results. The mods disabled in
+/// `mods.settings` are left out as the game doesn't load them.
+fn list_mods<'a>(
+  game_root: &Path, mod_settings: &'a ModSettings
+) -> impl Iterator<Item = DirEntry> + 'a {
+  let Ok(mods) = std::fs::read_dir(paths::mods_folder(game_root)) else {
     panic!("Could not read mods folder");
   };
 
-  mods.filter_map(|entry| match entry {
-    Ok(e) => Some(e),
-    Err(e) => {
-      println!("error reading mod: {e}");
+  mods
+    .filter_map(|entry| match entry {
+      Ok(e) => Some(e),
+      Err(e) => {
+        println!("error reading mod: {e}");
 
-      None
-    }
-  })
+        None
+      }
+    })
+    .filter(|module| {
+      let name = module.file_name();
+      let name = name.to_string_lossy();
+
+      // a `~` in front of the folder only hides the scripts of the mod from the
+      // game, its recipes are read as long as the mod isn't disabled
+      mod_settings.is_enabled(name.trim_start_matches('~'))
+    })
 }
 
 /// List the recipes for the given module, then parse them while also handling
 /// any eventual error during the process then return an iterator of the parsed
 /// directives from all recipes that were found.
 fn parse_dir_recipes<'a>(module: PathBuf) -> impl ParallelIterator<Item = Directive> + 'a {
+  let module_name = module_name(&module);
   let files = match read_dir_directive_files(&module) {
     Ok(f) => f,
     Err(e) => {
@@ -113,28 +261,43 @@ fn parse_dir_recipes<'a>(module: PathBuf) -> impl ParallelIterator<Item = Direct
 
   files
     .into_par_iter()
-    .filter_map(move |recipe| match parse_directive_file(recipe) {
-      Ok(directives) => Some(directives),
-      Err(e) => {
-        println!("error parsing recipe for {module:?}: {e}");
+    .filter_map(
+      move |(recipe, input)| match parse_directive_file(input, &module_name, &recipe) {
+        Ok(directives) => Some(directives),
+        Err(e) => {
+          println!("error parsing recipe for {module:?}: {e}");
 
-        None
+          None
+        }
       }
-    })
+    )
     .flat_map_iter(|directives| directives)
 }
 
-fn read_dir_directive_files(folder: &PathBuf) -> CResult<Vec<String>> {
+/// Deduce the name of the mod from its recipes folder, which is either the
+/// `cahirp` folder inside the mod or a folder supplied by `--recipes`.
+fn module_name(recipes_dir: &Path) -> String {
+  let folder = match recipes_dir.file_name() {
+    Some(name) if name == "cahirp" => recipes_dir.parent().and_then(|p| p.file_name()),
+    name => name
+  };
+
+  folder
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_default()
+}
+
+fn read_dir_directive_files(folder: &Path) -> CResult<Vec<(PathBuf, String)>> {
   match std::fs::read_dir(folder) {
     Err(_) => Ok(Vec::new()),
     Ok(dir) => {
       let mut output = Vec::new();
 
       for entry in dir {
-        let entry = entry?;
-        let content = read_file(&entry.path())?;
+        let path = entry?.path();
+        let content = read_file(&path)?;
 
-        output.push(content)
+        output.push((path, content))
       }
 
       Ok(output)
@@ -142,7 +305,14 @@ fn read_dir_directive_files(folder: &PathBuf) -> CResult<Vec<String>> {
   }
 }
 
-fn parse_directive_file(input: String) -> CResult<Vec<crate::parser::Directive>> {
+/// Get the 1-based line of the byte `offset` in `input`
+fn line_of(input: &str, offset: usize) -> usize {
+  input[..offset].matches('\n').count() + 1
+}
+
+fn parse_directive_file(
+  input: String, module: &str, recipe: &Path
+) -> CResult<Vec<crate::parser::Directive>> {
   let mut output = Vec::new();
 
   // since we do not parse the code that a directive emits (to speed things up)
@@ -153,8 +323,13 @@ fn parse_directive_file(input: String) -> CResult<Vec<crate::parser::Directive>>
   let mut slice = &input[..];
   let mut context = Context::empty();
 
+  // the position of the slice in the input, to know the line of the directives
+  let mut offset = 0;
+
   loop {
-    slice = slice.trim();
+    let trimmed = slice.trim_start();
+    offset += slice.len() - trimmed.len();
+    slice = trimmed.trim_end();
     let start = slice.find('@');
 
     if start.is_none() {
@@ -162,25 +337,235 @@ fn parse_directive_file(input: String) -> CResult<Vec<crate::parser::Directive>>
     }
 
     let _start = start.unwrap_or(0);
+    // the end is exclusive so the slices are cut on the `@` rather than on the
+    // last byte before it, which may be in the middle of a character
     let end = match slice[1..].find('@') {
-      Some(other) => other - 1,
-      None => slice.len() - 1
+      Some(other) => other + 1,
+      None => slice.len()
+    };
+    let directive_slice = slice[..end].trim_start();
+
+    let line = line_of(&input, offset);
+    let origin = Origin {
+      module: module.to_owned(),
+      recipe: recipe.to_path_buf(),
+      line,
+      end_line: line + directive_slice.trim_end().matches('\n').count()
     };
-    let directive_slice = slice[..=end].trim();
 
     match context.parse_with_context(directive_slice) {
       Err(e) => {
         println!("recipe syntax error: {e}");
       }
-      Ok(some_directive) => {
-        if let (_, Some(directive)) = some_directive {
-          output.push(directive);
-        }
+      Ok((_, None)) => context.set_origin(origin),
+      Ok((_, Some(mut directive))) => {
+        let recipe_name = origin.recipe_name();
+        directive.id = match directive.parameters().id() {
+          Some(id) => DirectiveId::derive(&[module, &recipe_name, id]),
+          None => DirectiveId::derive(&[module, &recipe_name, &directive.content()])
+        };
+
+        // the parameters from the context already have the origin of the
+        // context, the others are from the directive itself
+        directive.parameters_mut().set_origin(origin.clone());
+        directive.origin = origin;
+        output.push(directive);
       }
     }
 
     slice = &slice[end..];
+    offset += end;
   }
 
   Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PLAYER: &str =
+    "class CR4Player {\n\tfunction Repair() {\n\t\tvar x: int;\n\t\tx = 1;\n\t}\n}\n";
+  const INVENTORY: &str =
+    "class CInventoryComponent {\n\tevent OnItemAdded() {\n\t\tvar y: int;\n\t}\n}\n";
+
+  const RECIPE_A: &str = r#"
+@insert(
+  file(game/player.ws)
+  at(function Repair)
+  below(var x)
+)
+modA_first();
+
+@insert(
+  file(game/player.ws)
+  at(function Repair)
+  below(var x)
+)
+modA_second();
+
+@insert(
+  file(game/inventory.ws)
+  at(event OnItemAdded)
+  below(var y)
+)
+modA_inventory();
+"#;
+
+  const RECIPE_B: &str = r#"
+@insert(
+  file(game/player.ws)
+  at(function Repair)
+  below(var x)
+)
+modB_player();
+
+@insert(
+  file(game/player.ws)
+  at(function Repair)
+  select(x = 1;)
+)
+x = 2;
+"#;
+
+  // the same directive twice, located from its anchor
+  const RECIPE_C: &str = r#"
+@insert(
+  at(class CInventoryComponent)
+  below(var y)
+)
+modC_copy();
+
+@insert(
+  at(class CInventoryComponent)
+  below(var y)
+)
+modC_copy();
+"#;
+
+  /// Write a game with a few mods whose directives edit the same files, both at
+  /// the same places and at different ones
+  fn fixture(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("cahirp-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let files = [
+      ("content/content0/scripts/game/player.ws", PLAYER),
+      ("content/content0/scripts/game/inventory.ws", INVENTORY),
+      ("mods/modA/cahirp/a.ws", RECIPE_A),
+      ("mods/modB/cahirp/b.ws", RECIPE_B),
+      ("mods/modC/cahirp/c.ws", RECIPE_C)
+    ];
+
+    for (path, contents) in files {
+      let path = root.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, contents).unwrap();
+    }
+
+    root
+  }
+
+  fn options() -> BuildOptions {
+    BuildOptions {
+      clean_before_build: true,
+      without_mods: false,
+      strict: true,
+      markers: MarkerStyle::Full,
+      once: None,
+      atomic: false,
+      encoding: None,
+      mod_settings: ModSettings::default(),
+      recipes_dir: None,
+      dry_run: true,
+      patch: None,
+      check: false
+    }
+  }
+
+  /// A Fisher-Yates shuffle driven by a xorshift, so a failure can be replayed
+  fn shuffle<T>(items: &mut [T], mut seed: u64) {
+    for i in (1..items.len()).rev() {
+      seed ^= seed << 13;
+      seed ^= seed >> 7;
+      seed ^= seed << 17;
+
+      items.swap(i, (seed % (i as u64 + 1)) as usize);
+    }
+  }
+
+  /// Build the game in memory with the directives in the order the seed gives
+  /// them, before and after they're ordered.
+  fn build_shuffled(game_root: &Path, seed: u64) -> Vec<(PathBuf, String, Vec<DirectiveId>)> {
+    let options = options();
+    let out = game_root.join("out");
+
+    let (mut directives, mod_names) = read_directives(game_root, &options);
+    shuffle(&mut directives, seed);
+    order_directives(&mut directives);
+    shuffle(&mut directives, seed.rotate_left(32));
+
+    new_file_pool(directives, game_root, &out, &options)
+      .unwrap()
+      .emit(
+        &out,
+        &mod_names,
+        options.markers,
+        options.once,
+        options.atomic
+      )
+      .unwrap()
+      .into_files(&out)
+      .into_iter()
+      .map(|(suffix, file)| {
+        let ids = file.spans.iter().map(|span| span.directive).collect();
+
+        (suffix, file.contents, ids)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn builds_are_the_same_whatever_the_order_of_the_directives() {
+    let game_root = fixture("determinism");
+
+    let reference = build_shuffled(&game_root, 1);
+    let builds: Vec<_> = [0x2545f491, 0x9e3779b9, 0xdeadbeef]
+      .into_iter()
+      .map(|seed| build_shuffled(&game_root, seed))
+      .collect();
+
+    std::fs::remove_dir_all(&game_root).unwrap();
+
+    assert_eq!(reference.len(), 2);
+    for build in builds {
+      assert_eq!(build, reference);
+    }
+
+    // the directives of a file run in the order of the recipes, so the code
+    // inserted below the same line ends up in the reverse order
+    let (_, player, _) = &reference[1];
+    let order: Vec<usize> = ["modB_player", "modA_second", "modA_first", "x = 2;"]
+      .iter()
+      .map(|code| player.find(code).unwrap())
+      .collect();
+
+    assert!(order.is_sorted(), "{player}");
+  }
+
+  #[test]
+  fn copies_of_a_directive_get_their_own_id() {
+    let game_root = fixture("copies");
+    let (mut directives, _) = read_directives(&game_root, &options());
+    std::fs::remove_dir_all(&game_root).unwrap();
+
+    order_directives(&mut directives);
+    let copies: Vec<&Directive> = directives
+      .iter()
+      .filter(|d| d.code.contains("modC_copy"))
+      .collect();
+
+    assert_eq!(copies.len(), 2);
+    assert_ne!(copies[0].id, copies[1].id);
+  }
+}
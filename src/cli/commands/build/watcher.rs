@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
 use std::time::Duration;
 
@@ -74,18 +74,18 @@ pub fn build_and_watch(game_root: PathBuf, out: PathBuf, options: &BuildOptions)
 
   debouncer
     .watcher()
-    .watch(&folder_to_watch, RecursiveMode::Recursive)?;
+    .watch(folder_to_watch, RecursiveMode::Recursive)?;
 
   debouncer.watcher().unwatch(&out)?;
 
   debouncer
     .cache()
-    .add_root(&folder_to_watch, RecursiveMode::Recursive);
+    .add_root(folder_to_watch, RecursiveMode::Recursive);
 
   let mut counter = 0;
 
   // instantly perform a build when starting the watch mode:
-  handle_build(&game_root, &out, &options, &mut counter);
+  handle_build(&game_root, &out, options, &mut counter);
 
   ctrlc::set_handler(move || {
     if let Err(e) = tx.send(WatchEvent::BuildAndClose) {
@@ -102,12 +102,12 @@ pub fn build_and_watch(game_root: PathBuf, out: PathBuf, options: &BuildOptions)
         // BuildAndClose event.
         crate::cli::prints::clear();
 
-        handle_build(&game_root, &out, &options, &mut counter)
+        handle_build(&game_root, &out, options, &mut counter)
       }
       WatchEvent::BuildAndClose => {
         crate::cli::prints::clear();
         crate::cli::prints::watch_ctrlc();
-        handle_build(&game_root, &out, &options, &mut counter);
+        handle_build(&game_root, &out, options, &mut counter);
         break;
       }
     }
@@ -118,10 +118,10 @@ pub fn build_and_watch(game_root: PathBuf, out: PathBuf, options: &BuildOptions)
 
 /// Builds the recipes and safely handle any resulting error that may come from
 /// it
-fn handle_build(game_root: &PathBuf, out: &PathBuf, options: &BuildOptions, counter: &mut u64) {
+fn handle_build(game_root: &Path, out: &Path, options: &BuildOptions, counter: &mut u64) {
   let before = std::time::Instant::now();
 
-  match super::build(&game_root, &out, options) {
+  match super::build(game_root, out, options) {
     Ok(()) => {
       crate::cli::prints::watch_rebuild(*counter, out, before);
       *counter += 1;
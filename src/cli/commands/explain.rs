@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::error::CResult;
+use crate::parser::Directive;
+
+use super::build::{collect_directives, new_file_pool, BuildOptions};
+
+/// Print how the directives matching the selector are resolved and how their
+/// cursor moves in each file, the selector being either the id of a directive,
+/// the name given with its `id` parameter, a part of one of its notes, or the
+/// name of its export.
+pub fn explain(
+  game_root: &Path, out: &Path, options: &BuildOptions, selector: &str
+) -> CResult<()> {
+  let (directives, mod_names, export_db) = collect_directives(game_root, options)?;
+
+  let matches = |d: &Directive| {
+    d.id.to_string() == selector
+      || d.parameters().id() == Some(selector)
+      || d.parameters().notes().any(|note| note.contains(selector))
+  };
+
+  let exports: Vec<&Directive> = export_db
+    .exports()
+    .filter(|(key, d)| key.name == selector || key.to_string() == selector || matches(d))
+    .map(|(_, d)| d)
+    .collect();
+
+  let selected: Vec<&Directive> = directives.iter().filter(|d| matches(d)).collect();
+
+  if exports.is_empty() && selected.is_empty() {
+    crate::cli::prints::explain_no_match(selector);
+
+    return Ok(());
+  }
+
+  for directive in exports {
+    // exports aren't resolved during the build since they never run, so it is
+    // done here on a copy
+    let mut parameters = directive.parameters().clone();
+    let _ = parameters.feed_exports(&export_db, &directive.origin.module, &mut Vec::new());
+
+    crate::cli::prints::explain_parameters(directive, &parameters, true);
+  }
+
+  for directive in &selected {
+    crate::cli::prints::explain_parameters(directive, directive.parameters(), false);
+  }
+
+  let traced: HashSet<_> = selected.iter().map(|d| d.id).collect();
+
+  // the whole build runs in memory so the cursor moves over the files exactly
+  // like it would during a build, with the code the previous directives emitted
+  new_file_pool(directives, game_root, out, options)?
+    .trace(traced)
+    .emit(
+      out,
+      &mod_names,
+      options.markers,
+      options.once,
+      options.atomic
+    )?;
+
+  Ok(())
+}
@@ -0,0 +1,172 @@
+use std::path::{Path, PathBuf};
+
+use crate::codegen::SourceMap;
+use crate::encoding::read_file;
+use crate::error::CResult;
+
+/// An error or warning reported by the game's script compiler, for example:
+/// `Error [mod00000_cahirp]game\player\r4player.ws(1234): Could not find ...`
+pub struct CompilerError {
+  /// The name between brackets, which is the mod the file comes from
+  pub module: String,
+
+  /// Path of the file relative to the `content/scripts` folder
+  pub file: PathBuf,
+  pub line: usize,
+  pub message: String
+}
+
+impl CompilerError {
+  pub fn parse(line: &str) -> Option<Self> {
+    // the game lowercases most paths, only ascii letters are lowered here so
+    // the indices of both strings match.
+    let lowercase = line.to_ascii_lowercase();
+    let extension = lowercase.find(".ws(")?;
+    let path_end = extension + ".ws".len();
+
+    let (line_number, message) = line[path_end + 1..].split_once(')')?;
+    let line_number = line_number.trim().parse().ok()?;
+    let message = message.trim_start_matches(':').trim().to_owned();
+
+    let before = &line[..path_end];
+    let (module, path) = match before.rfind(']') {
+      Some(tag_end) => {
+        let tag_start = before[..tag_end].rfind('[').map(|i| i + 1).unwrap_or(0);
+
+        (
+          before[tag_start..tag_end].to_owned(),
+          &before[tag_end + 1..]
+        )
+      }
+      None => {
+        let path_start = before
+          .rfind(char::is_whitespace)
+          .map(|i| i + 1)
+          .unwrap_or(0);
+
+        (String::new(), &before[path_start..])
+      }
+    };
+
+    let path = path.trim().replace('\\', "/");
+
+    // absolute paths are turned into paths relative to the scripts folder,
+    // while keeping the name of the mod they come from
+    let (module, path) = match path.to_lowercase().rfind("/content/scripts/") {
+      Some(idx) => {
+        let folder = path[..idx].rsplit('/').next().unwrap_or("").to_owned();
+        let file = path[idx + "/content/scripts/".len()..].to_owned();
+
+        match module.is_empty() {
+          true => (folder, file),
+          false => (module, file)
+        }
+      }
+      None => (module, path)
+    };
+
+    Some(Self {
+      module,
+      file: PathBuf::from(path),
+      line: line_number,
+      message
+    })
+  }
+}
+
+/// Read the script compilation log and print the mod, recipe and directive
+/// responsible for each error that comes from the cahirp output.
+pub fn explain_errors(log: &Path, out: &Path) -> CResult<()> {
+  let log = read_file(log)?;
+  let source_map = SourceMap::read(out).unwrap_or_else(|e| {
+    println!("could not read the source map of {}: {e}", out.display());
+
+    SourceMap::default()
+  });
+
+  // the default output is `<mod>/content/scripts`, the errors refer to it
+  // through the name of the mod folder.
+  let out_module = out
+    .ancestors()
+    .nth(2)
+    .filter(|_| out.ends_with("content/scripts"))
+    .unwrap_or(out)
+    .file_name()
+    .map(|name| name.to_string_lossy().to_lowercase())
+    .unwrap_or_default();
+
+  for error in log.lines().filter_map(CompilerError::parse) {
+    let in_output = error.module.to_lowercase() == out_module;
+
+    crate::cli::prints::explain_error(&error);
+
+    if !in_output {
+      crate::cli::prints::explain_error_not_generated(&error.module);
+    } else {
+      match source_map.lookup(&error.file, error.line) {
+        Some(entry) => crate::cli::prints::explain_error_origin(entry),
+        None => crate::cli::prints::explain_error_base_file(
+          source_map.file_origin(&error.file),
+          source_map.base_line(&error.file, error.line)
+        )
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bracketed_paths() {
+    let error = CompilerError::parse(
+      "Error [mod00000_cahirp]game\\player\\r4Player.ws(1234): Could not find function 'Repair'"
+    )
+    .unwrap();
+
+    assert_eq!(error.module, "mod00000_cahirp");
+    assert_eq!(error.file, PathBuf::from("game/player/r4Player.ws"));
+    assert_eq!(error.line, 1234);
+    assert_eq!(error.message, "Could not find function 'Repair'");
+  }
+
+  #[test]
+  fn absolute_paths() {
+    let error = CompilerError::parse(
+      "Error C:\\Games\\The Witcher 3\\mods\\mod00000_cahirp\\content\\scripts\\game\\player\\r4Player.ws(12): Unexpected '}'"
+    )
+    .unwrap();
+
+    assert_eq!(error.module, "mod00000_cahirp");
+    assert_eq!(error.file, PathBuf::from("game/player/r4Player.ws"));
+    assert_eq!(error.line, 12);
+    assert_eq!(error.message, "Unexpected '}'");
+
+    let error = CompilerError::parse(
+      "Warning /games/the-witcher-3/mods/modA/content/scripts/game/inventory.ws(3): unused variable"
+    )
+    .unwrap();
+
+    assert_eq!(error.module, "modA");
+    assert_eq!(error.file, PathBuf::from("game/inventory.ws"));
+    assert_eq!(error.line, 3);
+  }
+
+  #[test]
+  fn backslash_paths() {
+    let error = CompilerError::parse("Error game\\inventory.ws(7): Missing ';'").unwrap();
+
+    assert_eq!(error.module, "");
+    assert_eq!(error.file, PathBuf::from("game/inventory.ws"));
+    assert_eq!(error.line, 7);
+  }
+
+  #[test]
+  fn lines_without_a_script() {
+    assert!(CompilerError::parse("Compilation started").is_none());
+    assert!(CompilerError::parse("Error game\\inventory.ws: no line").is_none());
+  }
+}
@@ -0,0 +1,299 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::codegen::{ExecutionOrchestrator, ExportDatabase, Stage};
+use crate::error::CResult;
+use crate::parser::{Directive, DirectiveId};
+
+use super::build::{parse_directives, BuildOptions};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum GraphFormat {
+  /// Graphviz DOT, to render with `dot -Tsvg`
+  Dot,
+  Json
+}
+
+/// The relations between the directives through their variables and exports
+#[derive(Serialize)]
+pub struct DependencyGraph {
+  pub directives: Vec<DirectiveNode>,
+  pub exports: Vec<ExportNode>
+}
+
+#[derive(Serialize)]
+pub struct DirectiveNode {
+  pub id: String,
+  pub module: String,
+  pub recipe: PathBuf,
+  pub line: usize,
+  pub notes: Vec<String>,
+  pub files: Vec<String>,
+  pub stage: i32,
+
+  /// The iteration of the orchestrator the directive ran in, `None` if its
+  /// requirements were never met
+  pub iteration: Option<usize>,
+  pub ifdefs: Vec<String>,
+  pub ifndefs: Vec<String>,
+  pub defines: Vec<String>,
+
+  /// The exports the directive uses directly, those they use in turn are
+  /// listed on the exports themselves
+  pub uses: Vec<String>
+}
+
+#[derive(Serialize)]
+pub struct ExportNode {
+  pub key: String,
+  pub recipe: PathBuf,
+  pub line: usize,
+  pub uses: Vec<String>
+}
+
+/// Output the dependency graph of the directives, either to the given file or
+/// to the standard output.
+pub fn graph(
+  game_root: &Path, options: &BuildOptions, format: GraphFormat, output: Option<&PathBuf>
+) -> CResult<()> {
+  let (mut directives, mod_names) = parse_directives(game_root, options);
+  let export_db = ExportDatabase::collect_named_exports(&mut directives);
+
+  // the uses are read before they're expanded, as the expansion adds the uses
+  // of the exports to the directive
+  let direct_uses: HashMap<DirectiveId, Vec<String>> = directives
+    .iter()
+    .map(|d| (d.id, resolve_uses(d, &export_db)))
+    .collect();
+
+  export_db.feed_exports(&mut directives, options.strict)?;
+
+  let iterations = run_iterations(&directives, &mod_names);
+  let graph = DependencyGraph {
+    directives: directives
+      .iter()
+      .map(|d| DirectiveNode {
+        id: d.id.to_string(),
+        module: d.origin.module.clone(),
+        recipe: d.origin.recipe.clone(),
+        line: d.origin.line,
+        notes: d.parameters().notes().map(str::to_owned).collect(),
+        files: d.parameters().files().map(str::to_owned).collect(),
+        stage: d.parameters().stage().0,
+        iteration: iterations.get(&d.id).copied(),
+        ifdefs: d.parameters().ifdefs().map(str::to_owned).collect(),
+        ifndefs: d.parameters().ifndefs().map(str::to_owned).collect(),
+        defines: d.parameters().defines().map(str::to_owned).collect(),
+        uses: direct_uses.get(&d.id).cloned().unwrap_or_default()
+      })
+      .collect(),
+    exports: {
+      let mut exports: Vec<ExportNode> = export_db
+        .exports()
+        .map(|(key, d)| ExportNode {
+          key: key.to_string(),
+          recipe: d.origin.recipe.clone(),
+          line: d.origin.line,
+          uses: resolve_uses(d, &export_db)
+        })
+        .collect();
+
+      exports.sort_by(|a, b| a.key.cmp(&b.key));
+      exports
+    }
+  };
+
+  let rendered = match format {
+    GraphFormat::Dot => graph.to_dot(),
+    GraphFormat::Json => serde_json::to_string_pretty(&graph)
+      .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+  };
+
+  match output {
+    Some(path) => {
+      std::fs::write(path, rendered)?;
+      crate::cli::prints::graph_written(path);
+    }
+    None => println!("{rendered}")
+  };
+
+  Ok(())
+}
+
+/// Get the full keys of the exports the directive uses, the ones that match no
+/// export are left out as they're reported when the uses are expanded.
+fn resolve_uses(directive: &Directive, export_db: &ExportDatabase) -> Vec<String> {
+  directive
+    .parameters()
+    .uses()
+    .filter_map(|key| export_db.get(key, &directive.origin.module).ok())
+    .map(|(key, _)| key.to_string())
+    .collect()
+}
+
+/// Run the orchestrator like a build would, without emitting any code, and
+/// return the iteration each directive ran in.
+fn run_iterations(
+  directives: &Vec<Directive>, mod_names: &[String]
+) -> HashMap<DirectiveId, usize> {
+  let installed = ExecutionOrchestrator::installed_variables(mod_names);
+  let mut iterations = HashMap::new();
+
+  ExecutionOrchestrator::run(directives, &installed, |to_run, iteration| {
+    for directive in to_run {
+      iterations.insert(directive.id, iteration);
+    }
+  });
+
+  iterations
+}
+
+impl DependencyGraph {
+  /// Render the graph in the DOT language, the directives are grouped by the
+  /// iteration and stage they ran in while the variables and exports link them together.
+  pub fn to_dot(&self) -> String {
+    let mut dot = String::new();
+    let mut variables = HashSet::new();
+
+    let _ = writeln!(dot, "digraph cahirp {{");
+    let _ = writeln!(dot, "  rankdir=LR;");
+    let _ = writeln!(dot, "  node [fontname=\"monospace\"];");
+
+    let mut by_iteration: Vec<(Option<usize>, Vec<&DirectiveNode>)> = Vec::new();
+    for directive in &self.directives {
+      match by_iteration
+        .iter_mut()
+        .find(|(i, _)| *i == directive.iteration)
+      {
+        Some((_, group)) => group.push(directive),
+        None => by_iteration.push((directive.iteration, vec![directive]))
+      }
+    }
+
+    // the directives that never ran come last
+    by_iteration.sort_by_key(|(i, _)| i.unwrap_or(usize::MAX));
+
+    for (iteration, group) in &by_iteration {
+      let (name, label) = match iteration {
+        // all the directives of an iteration are from the same stage
+        Some(i) => (
+          format!("iteration_{i}"),
+          format!("iteration {i}, stage {}", Stage(group[0].stage))
+        ),
+        None => ("never_ran".to_owned(), "never ran".to_owned())
+      };
+
+      let _ = writeln!(dot, "  subgraph cluster_{name} {{");
+      let _ = writeln!(dot, "    label=\"{label}\";");
+
+      for directive in group {
+        let recipe = directive
+          .recipe
+          .file_name()
+          .map(|name| name.to_string_lossy())
+          .unwrap_or_default();
+
+        let mut label = format!(
+          "#{} {}\n{recipe}:{}",
+          directive.id, directive.module, directive.line
+        );
+        for note in &directive.notes {
+          label.push('\n');
+          label.push_str(note);
+        }
+
+        let _ = writeln!(
+          dot,
+          "    \"directive:{}\" [shape=box, label=\"{}\"];",
+          directive.id,
+          escape(&label)
+        );
+      }
+
+      let _ = writeln!(dot, "  }}");
+    }
+
+    for export in &self.exports {
+      let _ = writeln!(
+        dot,
+        "  \"export:{}\" [shape=component, label=\"export {}\"];",
+        escape(&export.key),
+        escape(&export.key)
+      );
+
+      for used in &export.uses {
+        let _ = writeln!(
+          dot,
+          "  \"export:{}\" -> \"export:{}\" [style=dotted, label=\"use\"];",
+          escape(&export.key),
+          escape(used)
+        );
+      }
+    }
+
+    for directive in &self.directives {
+      let node = format!("directive:{}", directive.id);
+
+      for variable in &directive.defines {
+        variables.insert(variable);
+        let _ = writeln!(
+          dot,
+          "  \"{node}\" -> \"variable:{}\" [label=\"define\"];",
+          escape(variable)
+        );
+      }
+
+      for variable in &directive.ifdefs {
+        variables.insert(variable);
+        let _ = writeln!(
+          dot,
+          "  \"variable:{}\" -> \"{node}\" [label=\"ifdef\"];",
+          escape(variable)
+        );
+      }
+
+      for variable in &directive.ifndefs {
+        variables.insert(variable);
+        let _ = writeln!(
+          dot,
+          "  \"variable:{}\" -> \"{node}\" [style=dashed, label=\"ifndef\"];",
+          escape(variable)
+        );
+      }
+
+      for used in &directive.uses {
+        let _ = writeln!(
+          dot,
+          "  \"{node}\" -> \"export:{}\" [style=dotted, label=\"use\"];",
+          escape(used)
+        );
+      }
+    }
+
+    let mut variables: Vec<_> = variables.into_iter().collect();
+    variables.sort();
+
+    for variable in variables {
+      let _ = writeln!(
+        dot,
+        "  \"variable:{}\" [shape=ellipse, label=\"{}\"];",
+        escape(variable),
+        escape(variable)
+      );
+    }
+
+    let _ = writeln!(dot, "}}");
+
+    dot
+  }
+}
+
+fn escape(label: &str) -> String {
+  label
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
@@ -1,7 +1,19 @@
 use std::path::PathBuf;
 
+use crate::codegen::{MarkerStyle, OnceMode};
+use crate::encoding::TextEncoding;
+
 mod build;
-pub use build::{build, build_and_watch, BuildOptions};
+pub use build::{build, build_and_watch, BuildOptions, FileStatus};
+
+mod explain;
+pub use explain::explain;
+
+mod explain_errors;
+pub use explain_errors::{explain_errors, CompilerError};
+
+mod graph;
+pub use graph::{graph, GraphFormat};
 
 #[derive(Debug, clap::Subcommand)]
 pub enum Commands {
@@ -26,9 +38,103 @@ pub enum Commands {
     #[arg(short, long, action)]
     watch: bool,
 
+    /// Disables the use of the installed mods for generating the merged files, everything will be based off the content0 files.
+    #[arg(long, action)]
+    without_mods: bool,
+
+    /// Turns recipe warnings, like a `use` that matches no export, into errors that stop the build
+    #[arg(long, action)]
+    strict: bool,
+
+    /// Wraps every block of emitted code with comments telling which mod, recipe and directive it comes from
+    #[arg(long, value_enum, default_value_t = MarkerStyle::None)]
+    markers: MarkerStyle,
+
+    /// Skips the directives whose code is already present around their location, or in their scope, as if they all had a `once` parameter
+    #[arg(long, value_enum)]
+    once: Option<OnceMode>,
+
+    /// Applies the directives with several files in all of them or in none, a directive that finds no location in one of its files is rolled back from the others
+    #[arg(long, action)]
+    atomic: bool,
+
+    /// Writes every generated file in this encoding, by default a file keeps the encoding, the BOM and the line endings of the file it is based on
+    #[arg(long, value_enum)]
+    encoding: Option<TextEncoding>,
+
+    /// Runs the whole build but prints a unified diff of the changes for each file rather than writing to <OUT>
+    #[arg(long, action, conflicts_with = "watch")]
+    dry_run: bool,
+
+    /// Writes the diff of the dry run to the given patch file rather than printing it
+    #[arg(long, requires = "dry_run")]
+    patch: Option<PathBuf>,
+
+    /// Verifies <OUT> is up to date with the recipes without writing to it, lists the added, changed and stale files and fails if there is any
+    #[arg(long, action, conflicts_with_all = ["watch", "dry_run"])]
+    check: bool
+  },
+
+  /// Prints how a directive is resolved and how its cursor moves in each file,
+  /// step by step, along with the code around it
+  Explain {
+    /// The directive to explain: its id, the name given with its `id` parameter, a part of one of its notes, or the name of its export
+    #[arg(short, long)]
+    directive: String,
+
+    /// Path to game directory, defaults to the current working directory
+    #[arg(short, long)]
+    game: Option<PathBuf>,
+
+    /// Path to the output mod folder, defaults to "<GAME>/mods/mod00000_Cahirp/content/scripts"
+    #[arg(short, long)]
+    out: Option<PathBuf>,
+
+    /// A specific folder to read for recipes rather than all the mods from "<GAME>/mods"
+    #[arg(short, long)]
+    recipes: Option<PathBuf>,
+
     /// Disables the use of the installed mods for generating the merged files, everything will be based off the content0 files.
     #[arg(long, action)]
     without_mods: bool
+  },
+
+  /// Reads a script compilation error log from the game and prints the mod,
+  /// recipe and directive responsible for each error in the generated files
+  ExplainErrors {
+    /// Path to the file containing the errors reported by the script compiler
+    log: PathBuf,
+
+    /// Path to game directory, defaults to the current working directory
+    #[arg(short, long)]
+    game: Option<PathBuf>,
+
+    /// Path to the output mod folder that was built, defaults to "<GAME>/mods/mod00000_Cahirp/content/scripts"
+    #[arg(short, long)]
+    out: Option<PathBuf>
+  },
+
+  /// Outputs the dependency graph of the directives: the iteration each one
+  /// runs in, the variables that gate them and the exports they use
+  Graph {
+    /// Path to game directory, defaults to the current working directory
+    #[arg(short, long)]
+    game: Option<PathBuf>,
+
+    /// A specific folder to read for recipes rather than all the mods from "<GAME>/mods"
+    #[arg(short, long)]
+    recipes: Option<PathBuf>,
+
+    #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+    format: GraphFormat,
+
+    /// Writes the graph to the given file rather than printing it
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Turns recipe warnings, like a `use` that matches no export, into errors
+    #[arg(long, action)]
+    strict: bool
   }
 }
 
@@ -40,7 +146,15 @@ impl Default for Commands {
       recipes: None,
       clean: true,
       watch: false,
-      without_mods: false
+      without_mods: false,
+      strict: false,
+      markers: MarkerStyle::None,
+      once: None,
+      atomic: false,
+      encoding: None,
+      dry_run: false,
+      patch: None,
+      check: false
     }
   }
 }
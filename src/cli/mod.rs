@@ -1,6 +1,9 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use crate::encoding::TextEncoding;
 use crate::error::CResult;
+use crate::game::paths;
+use crate::game::settings::ModSettings;
 
 mod commands;
 pub use commands::Commands;
@@ -13,12 +16,27 @@ pub struct Cli {
   #[arg(short, long)]
   debug: bool,
 
+  /// Decodes the game and mod scripts without a BOM with this encoding rather than guessing it between UTF-8, UTF-16 and Windows-1252, the recipes are still detected
+  #[arg(long, value_enum, global = true)]
+  assume_encoding: Option<TextEncoding>,
+
+  /// Path to the documents folder of the game where its "mods.settings" file is, defaults to "Documents/The Witcher 3" in the home of the user, with Proton it is in the compatdata prefix of the game
+  #[arg(long, global = true)]
+  documents: Option<PathBuf>,
+
   #[command(subcommand)]
   pub command: Option<Commands>
 }
 
 impl Cli {
   pub fn execute(self) -> CResult<()> {
+    if let Some(encoding) = self.assume_encoding {
+      crate::encoding::assume_encoding(encoding);
+    }
+
+    let documents = self.documents.unwrap_or_else(paths::documents_folder);
+    let mod_settings = || ModSettings::load(&documents);
+
     match self.command.unwrap_or_default() {
       Commands::Build {
         game,
@@ -26,34 +44,36 @@ impl Cli {
         recipes,
         clean,
         watch,
-        without_mods
+        without_mods,
+        strict,
+        markers,
+        once,
+        atomic,
+        encoding,
+        dry_run,
+        patch,
+        check
       } => {
         // if using the default `out` folder it defaults to always cleaning
         // first
         let clean_before_build = clean || out.is_none();
 
-        let game_root = game.unwrap_or_else(|| {
-          #[cfg(debug_assertions)]
-          let path = Path::new("fake-game");
-
-          #[cfg(not(debug_assertions))]
-          let path = Path::new(".");
-
-          path.into()
-        });
-
-        let out = out.unwrap_or_else(|| {
-          game_root
-            .join("mods")
-            .join("mod00000_Cahirp")
-            .join("content")
-            .join("scripts")
-        });
+        let game_root = game_root(game);
+        let out = out_folder(out, &game_root);
 
         let options = commands::BuildOptions {
           clean_before_build,
           without_mods,
-          recipes_dir: recipes
+          strict,
+          markers,
+          once,
+          atomic,
+          encoding,
+          mod_settings: mod_settings(),
+          recipes_dir: recipes,
+          dry_run,
+          patch,
+          check
         };
 
         if watch {
@@ -62,8 +82,87 @@ impl Cli {
           commands::build(&game_root, &out, &options)
         }
       }
+      Commands::Explain {
+        directive,
+        game,
+        out,
+        recipes,
+        without_mods
+      } => {
+        let game_root = game_root(game);
+        let out = out_folder(out, &game_root);
+
+        // the build is made in memory as if the output was cleaned first
+        let options = commands::BuildOptions {
+          clean_before_build: true,
+          without_mods,
+          strict: false,
+          markers: Default::default(),
+          once: None,
+          atomic: false,
+          encoding: None,
+          mod_settings: mod_settings(),
+          recipes_dir: recipes,
+          dry_run: true,
+          patch: None,
+          check: false
+        };
+
+        commands::explain(&game_root, &out, &options, &directive)
+      }
+      Commands::ExplainErrors { log, game, out } => {
+        let game_root = game_root(game);
+        let out = out_folder(out, &game_root);
+
+        commands::explain_errors(&log, &out)
+      }
+      Commands::Graph {
+        game,
+        recipes,
+        format,
+        output,
+        strict
+      } => {
+        let game_root = game_root(game);
+
+        // only the recipes are read, no file is touched
+        let options = commands::BuildOptions {
+          clean_before_build: false,
+          without_mods: false,
+          strict,
+          markers: Default::default(),
+          once: None,
+          atomic: false,
+          encoding: None,
+          mod_settings: mod_settings(),
+          recipes_dir: recipes,
+          dry_run: true,
+          patch: None,
+          check: false
+        };
+
+        commands::graph(&game_root, &options, format, output.as_ref())
+      }
     }?;
 
     Ok(())
   }
 }
+
+/// Get the game directory, defaults to the current working directory
+fn game_root(game: Option<PathBuf>) -> PathBuf {
+  game.unwrap_or_else(|| {
+    #[cfg(debug_assertions)]
+    let path = Path::new("fake-game");
+
+    #[cfg(not(debug_assertions))]
+    let path = Path::new(".");
+
+    path.into()
+  })
+}
+
+/// Get the output folder, defaults to the scripts of the cahirp mod
+fn out_folder(out: Option<PathBuf>, game_root: &Path) -> PathBuf {
+  out.unwrap_or_else(|| paths::cahirp_scripts(game_root))
+}
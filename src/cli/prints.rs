@@ -1,9 +1,15 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use owo_colors::colors::*;
 use owo_colors::OwoColorize;
 
-use crate::parser::Parameters;
+use crate::cli::commands::{CompilerError, FileStatus};
+use crate::codegen::{
+  CodeCursor, ConflictGroups, ConflictKind, ExportKey, LocateError, Miss, SourceMapEntry,
+  UnmetCondition, UseError, INSTALLED_PREFIX
+};
+use crate::encoding::TextEncoding;
+use crate::parser::{Directive, DirectiveId, Parameters};
 
 mod badges {
 
@@ -33,6 +39,36 @@ mod badges {
     badge.fg::<White>().bg::<Red>().to_string()
   }
 
+  pub fn warn() -> String {
+    let badge = " WARN ";
+
+    badge.fg::<Black>().bg::<Yellow>().to_string()
+  }
+
+  pub fn error() -> String {
+    let badge = " ERROR ";
+
+    badge.fg::<White>().bg::<Red>().to_string()
+  }
+
+  pub fn conflict() -> String {
+    let badge = " CONFLICT ";
+
+    badge.fg::<Black>().bg::<Yellow>().to_string()
+  }
+
+  pub fn explain() -> String {
+    let badge = " EXPLAIN ";
+
+    badge.fg::<White>().bg::<Blue>().to_string()
+  }
+
+  pub fn skip() -> String {
+    let badge = " SKIP ";
+
+    badge.fg::<Black>().bg::<Green>().to_string()
+  }
+
   pub fn debug() -> String {
     let badge = " DEBUG ";
 
@@ -44,7 +80,7 @@ pub fn verbose_debug(message: String) {
   println!("{} {message}", badges::debug());
 }
 
-pub fn build(path: &PathBuf) {
+pub fn build(path: &Path) {
   println!("{} {}", badges::build(), path.display().green());
 }
 
@@ -54,13 +90,57 @@ pub fn clean_files() {
   println!("{spaces}└─ cleaning output directory");
 }
 
-pub fn watch(folder: &PathBuf) {
+pub fn dry_run_patch(patch: &Path) {
+  let spaces = " ".repeat(5);
+
+  println!(
+    "{spaces}└─ dry run, changes written to {}",
+    patch.display().green()
+  );
+}
+
+pub fn graph_written(path: &std::path::Path) {
+  println!(
+    "{} graph written to {}",
+    badges::build(),
+    path.display().green()
+  );
+}
+
+pub fn check_result(out: &Path, differences: &Vec<(PathBuf, FileStatus)>) {
+  let spaces = " ".repeat(5);
+
+  if differences.is_empty() {
+    println!("{spaces}└─ {} is up to date", out.display().green());
+
+    return;
+  }
+
+  println!(
+    "{spaces}└─ {} is outdated, {} files differ:",
+    out.display().green(),
+    differences.len()
+  );
+
+  let spaces = " ".repeat(8);
+  for (path, status) in differences {
+    let status = match status {
+      FileStatus::Added => "added".green().to_string(),
+      FileStatus::Changed => "changed".yellow().to_string(),
+      FileStatus::Stale => "stale".red().to_string()
+    };
+
+    println!("{spaces}└─ {status} {}", path.display());
+  }
+}
+
+pub fn watch(folder: &Path) {
   let badge = badges::watch();
 
   println!("{badge} {}", folder.display().green());
 }
 
-pub fn watch_rebuild(counter: u64, path: &PathBuf, instant: std::time::Instant) {
+pub fn watch_rebuild(counter: u64, path: &Path, instant: std::time::Instant) {
   let badge = badges::build();
   let counter = badges::counter(counter);
 
@@ -80,20 +160,507 @@ pub fn watch_ctrlc() {
   println!("{badge} building one last time and closing...");
 }
 
-pub fn build_no_location_found(path: &PathBuf, params: &Parameters) {
+pub fn build_no_location_found(
+  file_suffix: &std::path::Path, directive: &Directive, miss: Option<&Miss>
+) {
+  let badge = badges::miss();
+
+  linebreak();
+  println!(
+    "{badge} {} {directive} from {} at {} no location found",
+    file_suffix.display().green(),
+    directive.origin.module.green(),
+    directive.origin
+  );
+
+  let lines = miss.map(|miss| missed_step(directive, miss)).into_iter();
+  print_branches(lines.chain(directive.parameters().notes().map(str::to_owned)));
+}
+
+pub fn build_rolled_back(
+  directive: &Directive, file_suffix: &std::path::Path, all_suffixes: &[PathBuf],
+  miss: Option<&Miss>
+) {
   let badge = badges::miss();
 
   linebreak();
-  println!("{badge} {} no location found", path.display().green());
+  println!(
+    "{badge} {} {directive} from {} at {} no location found, rolled back",
+    file_suffix.display().green(),
+    directive.origin.module.green(),
+    directive.origin
+  );
+
+  let lines = miss
+    .map(|miss| missed_step(directive, miss))
+    .into_iter()
+    .chain(
+      all_suffixes
+        .iter()
+        .filter(|s| s.as_path() != file_suffix)
+        .map(|suffix| format!("not applied: {}", suffix.display()))
+    );
+
+  print_branches(lines.chain(directive.parameters().notes().map(str::to_owned)));
+}
+
+/// Describe the parameter the cursor got lost on, along with where it comes
+/// from when it isn't written on the directive itself.
+fn missed_step(directive: &Directive, miss: &Miss) -> String {
+  let step = miss.parameter.to_string();
+
+  match &miss.origin {
+    Some(origin) if origin.module != directive.origin.module => format!(
+      "lost at {} from {} at {origin}",
+      step.yellow(),
+      origin.module.green()
+    ),
+    Some(origin) if origin != &directive.origin => {
+      format!("lost at {} from {origin}", step.yellow())
+    }
+    _ => format!("lost at {}", step.yellow())
+  }
+}
+
+/// Print the lines as the branches of a tree, the last one closing it
+fn print_branches(lines: impl Iterator<Item = String>) {
+  let spaces = " ".repeat(4);
+  let mut lines = lines.peekable();
+
+  while let Some(line) = lines.next() {
+    let branch = match lines.peek().is_none() {
+      true => "└─",
+      false => "├─"
+    };
+
+    println!("{spaces}{branch} {line}");
+  }
+}
+
+pub fn encoding_unrepresentable(
+  file: &std::path::Path, encoding: TextEncoding, fallback: TextEncoding, chars: &[(usize, char)]
+) {
+  let badge = badges::warn();
+
+  linebreak();
+  println!(
+    "{badge} {} has characters that {} can't represent, it was written in {} instead",
+    file.display().green(),
+    encoding.yellow(),
+    fallback.yellow()
+  );
+
+  // the first ones are enough to find the text at fault
+  const SHOWN: usize = 10;
+  let lines = chars
+    .iter()
+    .take(SHOWN)
+    .map(|(line, c)| format!("line {line}: {} (U+{:04X})", c.yellow(), *c as u32));
+
+  let more = (chars.len() > SHOWN).then(|| format!("and {} more", chars.len() - SHOWN));
+  let hint = "pass --encoding to choose the encoding of the generated files".to_owned();
+
+  print_branches(lines.chain(more).chain(std::iter::once(hint)));
+}
+
+pub fn mod_settings_not_read(path: &std::path::Path, error: &std::io::Error) {
+  let badge = badges::warn();
+
+  linebreak();
+  println!(
+    "{badge} could not read {}: {error}, every mod is enabled and they're loaded in the order of their names",
+    path.display().green()
+  );
+
+  print_branches(std::iter::once(
+    "pass --documents to point to the folder of the game in the documents, with Proton it is in the compatdata prefix: compatdata/292030/pfx/drive_c/users/steamuser/Documents/The Witcher 3".to_owned()
+  ));
+}
+
+/// `script` tells whether the file is a script, the encoding of the other files
+/// can't be assumed.
+pub fn encoding_guessed(file: &str, encoding: TextEncoding, script: bool) {
+  let badge = badges::warn();
+
+  linebreak();
+  let reason = match encoding {
+    TextEncoding::Windows1252 => "is not valid UTF-8",
+    _ => "has no BOM"
+  };
+
+  println!(
+    "{badge} {} {reason}, it was read as {}",
+    file.green(),
+    encoding.yellow()
+  );
 
   let spaces = " ".repeat(4);
-  for note in params.notes() {
+  match script {
+    true => println!("{spaces}└─ pass --assume-encoding if this is not its encoding"),
+    false => println!("{spaces}└─ save it as UTF-8 if this is not its encoding")
+  };
+}
+
+pub fn file_pattern_no_match(directive: &Directive, pattern: &str) {
+  let badge = badges::warn();
+
+  linebreak();
+  println!(
+    "{badge} {directive} from {} at {} file({}) matches no file",
+    directive.origin.module.green(),
+    directive.origin,
+    pattern.green()
+  );
+}
+
+pub fn file_not_located(directive: &Directive, error: &LocateError, is_error: bool) {
+  let badge = match is_error {
+    true => badges::error(),
+    false => badges::warn()
+  };
+
+  linebreak();
+  match error {
+    LocateError::NoAnchor => println!(
+      "{badge} {directive} from {} at {} has no file and no anchor to locate it with",
+      directive.origin.module.green(),
+      directive.origin
+    ),
+    LocateError::NotFound(anchor) => println!(
+      "{badge} {directive} from {} at {} no script contains {}",
+      directive.origin.module.green(),
+      directive.origin,
+      anchor.green()
+    ),
+    LocateError::Ambiguous(anchor, candidates) => {
+      println!(
+        "{badge} {directive} from {} at {} {} is found in several scripts, a file() parameter is needed",
+        directive.origin.module.green(),
+        directive.origin,
+        anchor.green()
+      );
+
+      print_branches(candidates.iter().map(|suffix| suffix.display().to_string()));
+    }
+  }
+}
+
+pub fn build_already_present(file_suffix: &std::path::Path, directive: &Directive) {
+  let badge = badges::skip();
+
+  linebreak();
+  println!(
+    "{badge} {} {directive} code already present",
+    file_suffix.display().green()
+  );
+
+  let spaces = " ".repeat(4);
+  for note in directive.parameters().notes() {
     println!("{spaces}└─ {note}");
   }
 }
 
+pub fn export_duplicate(key: &ExportKey, kept: DirectiveId, ignored: DirectiveId) {
+  let badge = badges::warn();
+
+  linebreak();
+  println!("{badge} export {} is declared more than once", key.green());
+
+  let spaces = " ".repeat(4);
+  println!("{spaces}├─ kept: Directive(id={})", kept.magenta());
+  println!("{spaces}└─ ignored: Directive(id={})", ignored.magenta());
+}
+
+pub fn directive_id_duplicate(directive: &Directive, first_line: usize) {
+  let badge = badges::warn();
+
+  linebreak();
+  println!(
+    "{badge} id({}) is given to more than one directive in {}",
+    directive.parameters().id().unwrap_or_default().green(),
+    directive.origin.recipe.display()
+  );
+
+  let spaces = " ".repeat(4);
+  println!("{spaces}├─ kept by the directive at line {first_line}");
+  println!(
+    "{spaces}└─ the directive at line {} uses {directive} instead",
+    directive.origin.line
+  );
+}
+
+pub fn use_error(directive: &Directive, error: &UseError, is_error: bool) {
+  let badge = match is_error {
+    true => badges::error(),
+    false => badges::warn()
+  };
+
+  linebreak();
+  println!("{badge} {directive} {error}");
+
+  let spaces = " ".repeat(4);
+  for note in directive.parameters().notes() {
+    println!("{spaces}└─ {note}");
+  }
+}
+
+pub fn explain_error(error: &CompilerError) {
+  let badge = badges::error();
+
+  linebreak();
+  println!(
+    "{badge} {}({}) {}",
+    error.file.display().green(),
+    error.line,
+    error.message
+  );
+}
+
+pub fn explain_error_origin(entry: &SourceMapEntry) {
+  let spaces = " ".repeat(4);
+
+  println!("{spaces}├─ mod: {}", entry.module.green());
+  match entry.recipe_end_line > entry.recipe_line {
+    true => println!(
+      "{spaces}├─ recipe: {}:{}-{}",
+      entry.recipe.display(),
+      entry.recipe_line,
+      entry.recipe_end_line
+    ),
+    false => println!(
+      "{spaces}├─ recipe: {}:{}",
+      entry.recipe.display(),
+      entry.recipe_line
+    )
+  };
+  println!("{spaces}└─ Directive(id={})", entry.directive.magenta());
+}
+
+pub fn explain_error_base_file(origin: Option<&str>, line: usize) {
+  let spaces = " ".repeat(4);
+
+  match origin {
+    Some(origin) => println!(
+      "{spaces}└─ line not generated, comes from line {line} of the base file from {}",
+      origin.green()
+    ),
+    None => println!("{spaces}└─ line not generated, comes from line {line} of the base file")
+  };
+}
+
+pub fn explain_error_not_generated(module: &str) {
+  let spaces = " ".repeat(4);
+
+  println!(
+    "{spaces}└─ not in the cahirp output but in {}",
+    module.green()
+  );
+}
+
+pub fn explain_parameters(directive: &Directive, parameters: &Parameters, exported: bool) {
+  let badge = badges::explain();
+
+  linebreak();
+  println!(
+    "{badge} {directive} from {} at {}",
+    directive.origin.module.green(),
+    directive.origin
+  );
+
+  let spaces = " ".repeat(4);
+  if exported {
+    println!(
+      "{spaces}├─ exported directive, it only emits code through the directives that use it"
+    );
+  }
+
+  println!("{spaces}└─ resolved parameters:");
+  for (param, origin) in parameters.with_origins() {
+    match origin {
+      // the parameters that come from an @context or an export are followed
+      // by where they were written
+      Some(origin) if origin != &directive.origin => println!(
+        "{spaces}     {param}  {}",
+        format!("from {} at {origin}", origin.module).dimmed()
+      ),
+      _ => println!("{spaces}     {param}")
+    }
+  }
+}
+
+pub fn explain_no_match(selector: &str) {
+  let badge = badges::miss();
+
+  linebreak();
+  println!("{badge} no directive matches {}", selector.green());
+}
+
+pub fn explain_cursor(directive: &Directive, file_suffix: &Path, file: &str, cursor: &CodeCursor) {
+  let badge = badges::explain();
+
+  linebreak();
+  println!("{badge} {directive} in {}", file_suffix.display().green());
+
+  let spaces = " ".repeat(4);
+  for step in cursor.trace.iter().flatten() {
+    println!(
+      "{spaces}├─ {} -> {}",
+      step.parameter,
+      describe_position(file, step.idx)
+    );
+
+    if file.is_char_boundary(step.idx) {
+      explain_code_around(file, step.idx, &format!("{spaces}│  "));
+    }
+  }
+
+  let pos = &cursor.pos;
+  let result = match (
+    file.is_char_boundary(pos.idx),
+    pos.selection_len,
+    pos.inline
+  ) {
+    (false, _, _) => "no location found".red().to_string(),
+    (true, 0, true) => format!("inline insertion at {}", describe_position(file, pos.idx)),
+    (true, 0, false) => format!("insertion at {}", describe_position(file, pos.idx)),
+    (true, len, _) => format!(
+      "selection from {} to {}",
+      describe_position(file, pos.idx),
+      describe_position(file, pos.idx + len)
+    )
+  };
+
+  println!("{spaces}└─ {result}");
+}
+
+/// Describe the line & column of the byte at `idx` in the file, both 1-based
+fn describe_position(file: &str, idx: usize) -> String {
+  match file.get(..idx) {
+    None => "end of file (pattern not found)".to_owned(),
+    Some(before) => {
+      let line = before.matches('\n').count() + 1;
+      let column = idx - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+
+      format!("line {line}, column {column}")
+    }
+  }
+}
+
+/// Print the lines around the byte at `idx`, the line of the byte is marked
+fn explain_code_around(file: &str, idx: usize, prefix: &str) {
+  const RADIUS: usize = 2;
+
+  let line = file[..idx].matches('\n').count();
+  let first = line.saturating_sub(RADIUS);
+
+  for (i, code) in file.lines().enumerate().skip(first).take(RADIUS * 2 + 1) {
+    let marker = match i == line {
+      true => ">".magenta().to_string(),
+      false => " ".to_owned()
+    };
+
+    println!(
+      "{prefix}{marker} {:>6} | {}",
+      i + 1,
+      code.replace('\t', "  ")
+    );
+  }
+}
+
+pub fn conflicts(groups: &ConflictGroups) {
+  let badge = badges::conflict();
+  let spaces = " ".repeat(4);
+
+  for ((first, second), conflicts) in groups {
+    linebreak();
+    match first == second {
+      true => println!("{badge} within {}", first.green()),
+      false => println!("{badge} between {} and {}", first.green(), second.green())
+    };
+
+    for (i, conflict) in conflicts.iter().enumerate() {
+      let branch = match i + 1 == conflicts.len() {
+        true => "└─",
+        false => "├─"
+      };
+
+      let description = match conflict.kind {
+        ConflictKind::OverlappingSelection => "selects code that was already replaced by",
+        ConflictKind::CutsGeneratedCode => "selects a part of the code generated by",
+        ConflictKind::InsertsIntoGeneratedCode => {
+          "inserts code in the middle of the code generated by"
+        }
+        ConflictKind::DuplicateInsertion => "inserts the exact same code as"
+      };
+
+      println!(
+        "{spaces}{branch} {}: Directive(id={}) {description} Directive(id={})",
+        conflict.file.display(),
+        conflict.second.magenta(),
+        conflict.first.magenta()
+      );
+    }
+  }
+}
+
+pub fn never_ran(directives: &[(&Directive, Vec<UnmetCondition>)]) {
+  let badge = badges::warn();
+  let spaces = " ".repeat(4);
+
+  for (directive, unmet) in directives {
+    linebreak();
+    println!(
+      "{badge} {directive} from {} at {} never ran",
+      directive.origin.module.green(),
+      directive.origin
+    );
+
+    let notes: Vec<_> = directive.parameters().notes().collect();
+    let count = unmet.len() + notes.len();
+
+    let lines = unmet.iter().map(|condition| match condition {
+      UnmetCondition::Undefined { variable, similar } => match similar.is_empty() {
+        true => format!("ifdef({}) is never defined", variable.green()),
+        false => format!(
+          "ifdef({}) is never defined, similar defines: {}",
+          variable.green(),
+          similar.join(", ").yellow()
+        )
+      },
+      UnmetCondition::NotInstalled { module, similar } => {
+        let variable = format!("{INSTALLED_PREFIX}{module}");
+
+        match similar.is_empty() {
+          true => format!("ifdef({}) mod {module} is not installed", variable.green()),
+          false => format!(
+            "ifdef({}) mod {module} is not installed, similar mods: {}",
+            variable.green(),
+            similar.join(", ").yellow()
+          )
+        }
+      }
+      UnmetCondition::Defined { variable } => format!("ifndef({}) is defined", variable.green()),
+      UnmetCondition::StageOver { stage } => {
+        format!("its requirements were only met once stage({stage}) was over")
+      }
+    });
+
+    for (i, line) in lines
+      .chain(notes.iter().map(|note| note.to_string()))
+      .enumerate()
+    {
+      let branch = match i + 1 == count {
+        true => "└─",
+        false => "├─"
+      };
+
+      println!("{spaces}{branch} {line}");
+    }
+  }
+}
+
 pub fn linebreak() {
-  println!("");
+  println!();
 }
 
 pub fn clear() {
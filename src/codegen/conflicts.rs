@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use crate::parser::{Directive, DirectiveId};
+
+/// The ways two directives can step on each other's toes in a single file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictKind {
+  /// The directive selected code that another directive had selected and
+  /// replaced before it
+  OverlappingSelection,
+
+  /// The directive selected a part of the code another directive inserted
+  CutsGeneratedCode,
+
+  /// The directive inserted its code in the middle of the code another
+  /// directive inserted or replaced
+  InsertsIntoGeneratedCode,
+
+  /// The directive inserted the exact same code as another directive, right
+  /// next to it
+  DuplicateInsertion
+}
+
+/// A conflict found while a directive emitted code, `first` being the directive
+/// that edited the file before `second`.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+  pub file: PathBuf,
+  pub kind: ConflictKind,
+  pub first: DirectiveId,
+  pub second: DirectiveId
+}
+
+/// The conflicts involving the same mods, the pair of mods is sorted so the
+/// conflicts between two mods are grouped whatever the order they ran in.
+pub type ConflictGroups<'a> = BTreeMap<(&'a str, &'a str), Vec<&'a Conflict>>;
+
+pub fn group_by_mods<'a>(
+  conflicts: &'a Vec<Conflict>, directives: &HashMap<DirectiveId, &'a Directive>
+) -> ConflictGroups<'a> {
+  let mut groups = ConflictGroups::new();
+
+  for conflict in conflicts {
+    let module = |id| {
+      directives
+        .get(&id)
+        .map(|d: &&Directive| d.origin.module.as_str())
+        .unwrap_or_default()
+    };
+
+    let first = module(conflict.first);
+    let second = module(conflict.second);
+    let key = match first <= second {
+      true => (first, second),
+      false => (second, first)
+    };
+
+    groups.entry(key).or_default().push(conflict);
+  }
+
+  groups
+}
+
+/// Collapse the whitespace of the code so two blocks that only differ by their
+/// indentation are considered identical.
+pub fn normalize_code(code: &str) -> String {
+  code.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn directive(module: &str) -> Directive {
+    let (_, mut directive) = Directive::parse("@insert(\n  at(class A)\n)\nfoo();").unwrap();
+    directive.origin.module = module.to_owned();
+    directive.id = DirectiveId::derive(&[module]);
+
+    directive
+  }
+
+  fn conflict(first: &Directive, second: &Directive) -> Conflict {
+    Conflict {
+      file: PathBuf::from("game/player.ws"),
+      kind: ConflictKind::DuplicateInsertion,
+      first: first.id,
+      second: second.id
+    }
+  }
+
+  #[test]
+  fn conflicts_are_grouped_by_pair_of_mods() {
+    let (a, b, c) = (directive("modA"), directive("modB"), directive("modC"));
+    let directives: HashMap<DirectiveId, &Directive> =
+      [&a, &b, &c].into_iter().map(|d| (d.id, d)).collect();
+
+    let conflicts = vec![conflict(&b, &a), conflict(&c, &b), conflict(&a, &b)];
+    let groups = group_by_mods(&conflicts, &directives);
+
+    let sizes: Vec<((&str, &str), usize)> = groups
+      .iter()
+      .map(|(mods, conflicts)| (*mods, conflicts.len()))
+      .collect();
+    assert_eq!(sizes, [(("modA", "modB"), 2), (("modB", "modC"), 1)]);
+  }
+
+  #[test]
+  fn normalized_code() {
+    assert_eq!(
+      normalize_code("\tif (x) {\n\t\ty();\n\t}\n"),
+      "if (x) { y(); }"
+    );
+  }
+}
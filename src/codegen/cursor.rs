@@ -1,12 +1,33 @@
 use std::str::Lines;
 
-use crate::parser::{Parameter, Parameters};
-
-use super::ExportDatabase;
+use crate::parser::{Origin, Parameter, Parameters};
 
 #[derive(Debug)]
 pub struct CodeCursor {
-  pub pos: CursorPosition
+  pub pos: CursorPosition,
+
+  /// When set, the position of the cursor is recorded after each step
+  pub trace: Option<Vec<CursorStep>>,
+
+  /// The first parameter that moved the cursor past the end of the file
+  pub missed: Option<Miss>
+}
+
+/// A parameter the cursor couldn't find a location for, along with where it
+/// was written since it can come from an `@context` or an export.
+#[derive(Debug, Clone)]
+pub struct Miss {
+  pub parameter: Parameter,
+  pub origin: Option<Origin>
+}
+
+/// The position the cursor reached after a parameter moved it
+#[derive(Debug)]
+pub struct CursorStep {
+  pub parameter: Parameter,
+  pub idx: usize,
+  pub selection_len: usize,
+  pub inline: bool
 }
 
 #[derive(Debug)]
@@ -15,6 +36,10 @@ pub struct CursorPosition {
   pub idx: usize,
   pub selection_len: usize,
 
+  /// Whether the cursor is inside a line rather than at its start, in which
+  /// case the code is emitted inline.
+  pub inline: bool,
+
   _prev_line_len: usize
 }
 
@@ -24,6 +49,7 @@ impl CursorPosition {
       // line: 0,
       idx: 0,
       selection_len: 0,
+      inline: false,
 
       _prev_line_len: 0
     }
@@ -40,13 +66,53 @@ impl CursorPosition {
 
     line
   }
+
+  /// Move the cursor to the given position in the file, the returned lines
+  /// start from the new position so the next line-based steps continue from
+  /// there.
+  fn jump_to<'a>(&mut self, file: &'a str, idx: usize) -> std::iter::Peekable<Lines<'a>> {
+    self.idx = idx;
+    self._prev_line_len = 0;
+
+    file[idx..].lines().peekable()
+  }
+
+  /// Move the cursor past the end of the file, which is how a missed pattern
+  /// is represented.
+  fn miss<'a>(&mut self, file: &'a str) -> std::iter::Peekable<Lines<'a>> {
+    self.idx = file.len() + 1;
+    self._prev_line_len = 0;
+
+    "".lines().peekable()
+  }
+
+  /// Move the cursor to the start of the next line if it's inside a line, so
+  /// the line-based steps that follow an inline one don't match the rest of the
+  /// current line.
+  fn leave_line<'a>(
+    &mut self, file: &'a str, lines: std::iter::Peekable<Lines<'a>>
+  ) -> std::iter::Peekable<Lines<'a>> {
+    if !self.inline {
+      return lines;
+    }
+
+    self.inline = false;
+    match self.remaining(file).find('\n') {
+      Some(newline) => self.jump_to(file, self.idx + newline + 1),
+      None => self.jump_to(file, file.len())
+    }
+  }
+
+  fn remaining<'a>(&self, file: &'a str) -> &'a str {
+    file.get(self.idx..).unwrap_or("")
+  }
 }
 
 impl CodeCursor {
-  pub fn advance(&mut self, params: &Parameters, export_db: &ExportDatabase, file: &str) {
+  pub fn advance(&mut self, params: &Parameters, file: &str) {
     let mut lines = file.lines().peekable();
 
-    for param in params.all() {
+    for (param, origin) in params.with_origins() {
       match param {
         Parameter::File(_) => continue,
         Parameter::Note(_) => continue,
@@ -54,48 +120,100 @@ impl CodeCursor {
         Parameter::IfNotDef(_) => continue,
         Parameter::Define(_) => continue,
         Parameter::Export(_) => continue,
-        Parameter::Use(key) => continue,
+        Parameter::Indent(_) => continue,
+        Parameter::Once(_) => continue,
+        Parameter::Stage(_) => continue,
+        Parameter::Id(_) => continue,
+        Parameter::Use(_) => continue,
         Parameter::UseConstructed(params) => {
-          self.advance(params, export_db, file);
+          self.advance(params, file);
+          continue;
         }
         Parameter::At(pat) => {
+          lines = self.pos.leave_line(file, lines);
+          let mut found = false;
           while let Some(line) = self.pos.next_line(&mut lines) {
             if line.contains(pat) {
+              found = true;
               break;
             }
           }
+
+          // a file that ends with a newline would otherwise leave the cursor
+          // right at its end, which is a valid position
+          if !found {
+            lines = self.pos.miss(file);
+          }
         }
         Parameter::Below(pat) => {
+          lines = self.pos.leave_line(file, lines);
+          let mut found = false;
           while let Some(line) = self.pos.next_line(&mut lines) {
             if line.contains(pat) {
+              found = true;
               break;
             }
           }
-          self.pos.next_line(&mut lines);
+
+          match found {
+            true => {
+              self.pos.next_line(&mut lines);
+            }
+            false => lines = self.pos.miss(file)
+          };
         }
         Parameter::Above(pat) => {
-          while let Some(_) = self.pos.next_line(&mut lines) {
+          lines = self.pos.leave_line(file, lines);
+          let mut found = false;
+          while self.pos.next_line(&mut lines).is_some() {
             if let Some(peek) = lines.peek() {
               if peek.contains(pat) {
+                found = true;
                 break;
               }
             }
           }
+
+          if !found {
+            lines = self.pos.miss(file);
+          }
         }
+        Parameter::After(pat) => match self.pos.remaining(file).find(pat) {
+          Some(pat_idx) => {
+            lines = self.pos.jump_to(file, self.pos.idx + pat_idx + pat.len());
+            self.pos.selection_len = 0;
+            self.pos.inline = true;
+          }
+          None => lines = self.pos.miss(file)
+        },
+        Parameter::Before(pat) => match self.pos.remaining(file).find(pat) {
+          Some(pat_idx) => {
+            lines = self.pos.jump_to(file, self.pos.idx + pat_idx);
+            self.pos.selection_len = 0;
+            self.pos.inline = true;
+          }
+          None => lines = self.pos.miss(file)
+        },
         Parameter::Select(pat) => {
-          let current_slice = &file[self.pos.idx..];
-          if let Some(pat_idx) = current_slice.find(pat) {
-            let pat_len = pat.len();
+          self.pos.inline = false;
+          let current_slice = self.pos.remaining(file);
+          match current_slice.find(pat) {
+            Some(pat_idx) => {
+              let pat_len = pat.len();
 
-            lines = current_slice[pat_idx..pat_idx + pat_len].lines().peekable();
-            self.pos.idx += pat_idx;
-            self.pos.selection_len = pat_len;
+              lines = current_slice[pat_idx..pat_idx + pat_len].lines().peekable();
+              self.pos.idx += pat_idx;
+              self.pos.selection_len = pat_len;
+            }
+            None => lines = self.pos.miss(file)
           }
         }
         Parameter::MultilineSelect(pat) => {
           let pat = pat.trim();
+          self.pos.inline = false;
 
-          'outer: while let Some(_) = self.pos.next_line(&mut lines) {
+          let mut found = false;
+          'outer: while self.pos.next_line(&mut lines).is_some() {
             let slice = &file[self.pos.idx..];
             let mut inner_lines = slice.lines();
 
@@ -123,18 +241,151 @@ impl CodeCursor {
 
             self.pos.selection_len = internal_idx - self.pos.idx;
             // pos.idx = internal_idx;
+            found = true;
             break 'outer;
           }
+
+          if !found {
+            lines = self.pos.miss(file);
+          }
         }
       }
+
+      if self.missed.is_none() && self.pos.idx > file.len() {
+        self.missed = Some(Miss {
+          parameter: param.clone(),
+          origin: origin.cloned()
+        });
+      }
+
+      if let Some(trace) = &mut self.trace {
+        trace.push(CursorStep {
+          parameter: param.clone(),
+          idx: self.pos.idx,
+          selection_len: self.pos.selection_len,
+          inline: self.pos.inline
+        });
+      }
     }
   }
-  pub fn from_parameters(params: &Parameters, export_db: &ExportDatabase, file: &str) -> Self {
+
+  pub fn from_parameters(params: &Parameters, file: &str) -> Self {
     let mut s = Self {
-      pos: CursorPosition::new()
+      pos: CursorPosition::new(),
+      trace: None,
+      missed: None
     };
 
-    s.advance(params, export_db, file);
+    s.advance(params, file);
     s
   }
+
+  /// Like [CodeCursor::from_parameters] but records every step of the cursor
+  /// in [CodeCursor::trace]
+  pub fn traced(params: &Parameters, file: &str) -> Self {
+    let mut s = Self {
+      pos: CursorPosition::new(),
+      trace: Some(Vec::new()),
+      missed: None
+    };
+
+    s.advance(params, file);
+    s
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FILE: &str = "class A {\n\tfunction f() {\n\t\tfoo(); bar();\n\t\tbaz();\n\t}\n}\n";
+
+  fn cursor(parameters: &[&str]) -> CodeCursor {
+    let (_, parameters) = Parameters::parse(&format!("{}\n", parameters.join("\n"))).unwrap();
+
+    CodeCursor::from_parameters(&parameters, FILE)
+  }
+
+  #[test]
+  fn line_steps() {
+    assert_eq!(
+      cursor(&["at(function f)"]).pos.idx,
+      FILE.find("\tfunction").unwrap()
+    );
+    assert_eq!(
+      cursor(&["below(function f)"]).pos.idx,
+      FILE.find("\t\tfoo").unwrap()
+    );
+    assert_eq!(
+      cursor(&["above(baz)"]).pos.idx,
+      FILE.find("\t\tfoo").unwrap()
+    );
+  }
+
+  #[test]
+  fn inline_steps() {
+    let after = cursor(&["after(foo();)"]);
+    assert_eq!(after.pos.idx, FILE.find(" bar").unwrap());
+    assert!(after.pos.inline);
+
+    let before = cursor(&["select(foo();)", "before(bar)"]);
+    assert_eq!(before.pos.idx, FILE.find("bar").unwrap());
+    assert_eq!(before.pos.selection_len, 0);
+  }
+
+  #[test]
+  fn line_steps_after_an_inline_one_start_on_the_next_line() {
+    let missed = cursor(&["after(foo();)", "at(bar)"]);
+    assert!(missed.pos.idx > FILE.len());
+    assert!(matches!(
+      missed.missed,
+      Some(Miss {
+        parameter: Parameter::At(_),
+        ..
+      })
+    ));
+
+    let found = cursor(&["after(foo();)", "at(baz)"]);
+    assert_eq!(found.pos.idx, FILE.find("\t\tbaz").unwrap());
+    assert!(!found.pos.inline);
+  }
+
+  #[test]
+  fn select() {
+    let selected = cursor(&["at(function f)", "select(bar();)"]);
+    assert_eq!(selected.pos.idx, FILE.find("bar").unwrap());
+    assert_eq!(selected.pos.selection_len, "bar();".len());
+
+    let block = cursor(&["select[[\n  foo(); bar();\n  baz();\n]]"]);
+    assert_eq!(block.pos.idx, FILE.find("\t\tfoo").unwrap());
+    assert_eq!(
+      block.pos.selection_len,
+      "\t\tfoo(); bar();\n\t\tbaz();\n".len()
+    );
+
+    let missed = cursor(&["select(qux();)"]);
+    assert!(missed.pos.idx > FILE.len());
+    assert!(matches!(
+      missed.missed,
+      Some(Miss {
+        parameter: Parameter::Select(_),
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn missed_line_steps() {
+    for parameter in [
+      "at(qux)",
+      "below(qux)",
+      "above(qux)",
+      "select[[\n  qux();\n  quux();\n]]"
+    ] {
+      let missed = cursor(&[parameter]);
+
+      assert!(missed.pos.idx > FILE.len(), "{parameter}");
+      assert!(missed.missed.is_some(), "{parameter}");
+    }
+  }
 }
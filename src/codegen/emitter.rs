@@ -1,57 +1,214 @@
-use crate::parser::Parameters;
+use crate::parser::{DirectiveId, Parameter, Parameters};
 
-use super::{CodeCursor, ExportDatabase};
+use super::indentation::indent_code;
+use super::markers::is_marked;
+use super::once::contains_code;
+use super::{CodeCursor, Edit, IndentMode, Markers, Miss, OnceMode};
+
+/// The options of the build and of the directive that affect how it emits its
+/// code
+#[derive(Default)]
+pub struct EmitOptions<'a> {
+  pub markers: Option<&'a Markers>,
+
+  /// The [OnceMode] of the directives that don't set their own
+  pub once: Option<OnceMode>,
+
+  /// The mod and the id of the directive, to recognize the code it emitted in
+  /// an earlier build from its markers
+  pub module: &'a str,
+  pub id: DirectiveId
+}
+
+pub enum Emission {
+  /// The code was emitted, the [Edit] describes where
+  Emitted(String, Edit),
+
+  /// The directive is `once` and its code is already in the file, which is
+  /// returned untouched
+  AlreadyPresent(String),
+
+  /// The cursor found no location, the file is returned untouched along with
+  /// the parameter the cursor got lost on
+  NoLocation(String, Option<Miss>)
+}
 
 pub trait CodeEmitter {
   fn parameters(&self) -> &Parameters;
   fn parameters_mut(&mut self) -> &mut Parameters;
 
-  fn emit(
-    &self, mut file: String, code: &str, export_db: &ExportDatabase
-  ) -> Result<String, String> {
+  fn emit(&self, file: String, code: &str, options: &EmitOptions) -> Emission {
     let params = self.parameters();
-    let cursor = CodeCursor::from_parameters(params, export_db, &file);
+    let once = params.once_mode().or(options.once);
+
+    // the markers identify the code wherever it is, and the emission may have
+    // removed what the cursor looks for
+    if once.is_some() && is_marked(&file, options.module, options.id, code) {
+      return Emission::AlreadyPresent(file);
+    }
+
+    let cursor = CodeCursor::from_parameters(params, &file);
 
     // the cursor itself has no notion of validity, here we check whether the
     // resulting position is out of bound which means no valid position was
     // found as the cursor looped until the EOF.
     if !file.is_char_boundary(cursor.pos.idx) {
-      return Err(file);
+      // a select replaces its pattern with the code, so once it was applied the
+      // pattern is gone and the code is there instead
+      let replaced = matches!(
+        cursor.missed.as_ref().map(|miss| &miss.parameter),
+        Some(Parameter::Select(_) | Parameter::MultilineSelect(_))
+      );
+
+      if once.is_some() && replaced && contains_code(&file, code) {
+        return Emission::AlreadyPresent(file);
+      }
+
+      return Emission::NoLocation(file, cursor.missed);
     }
 
-    let (left, right) = file.split_at_mut(cursor.pos.idx);
+    if let Some(once) = once {
+      if once.is_present(&file, cursor.pos.idx, cursor.pos.selection_len, code) {
+        return Emission::AlreadyPresent(file);
+      }
+    }
+
+    let (left, right) = file.split_at(cursor.pos.idx);
 
     let mut output = String::with_capacity(left.len() + code.len() + right.len());
-    output.push_str(left.trim_end_matches('\t').trim_end_matches(' '));
-    output.push_str(&match_line_indentation(code, left));
-    output.push_str(&right[cursor.pos.selection_len..]);
+    if cursor.pos.inline {
+      // inline code is inserted as is, the surrounding line already has its
+      // indentation and its newline
+      output.push_str(left);
+      output.push_str(code.trim_matches(['\r', '\n']));
+    } else {
+      output.push_str(left.trim_end_matches('\t').trim_end_matches(' '));
+
+      // markers are always aligned on the target line, whatever the indentation
+      // mode of the code
+      if let Some(markers) = options.markers {
+        output.push_str(&indent_code(&markers.begin, left, &file, IndentMode::Auto));
+      }
+
+      output.push_str(&indent_code(code, left, &file, params.indent_mode()));
+
+      if let Some(markers) = options.markers {
+        output.push_str(&indent_code(&markers.end, left, &file, IndentMode::Auto));
+      }
+    }
+
+    let right = &right[cursor.pos.selection_len..];
+    let edit_start = match cursor.pos.inline {
+      true => left.len(),
+      false => left.trim_end_matches('\t').trim_end_matches(' ').len()
+    };
+
+    let edit = Edit {
+      start: edit_start,
+      removed: file.len() - right.len() - edit_start,
+      inserted: output.len() - edit_start,
+      selection: cursor.pos.selection_len > 0
+    };
 
-    Ok(output)
+    output.push_str(right);
+
+    Emission::Emitted(output, edit)
   }
 }
 
-/// generates a string of code whose indentation matches the last line of
-/// `surrounding`
-fn match_line_indentation(code: &str, surrounding: &str) -> String {
-  let segment: String = surrounding
-    .chars()
-    .rev()
-    .skip_while(|c| c != &'\n')
-    .skip(1)
-    .take_while(|c| c != &'\n')
-    .collect();
-
-  // trim_end as it is reversed so it is actually the start of the line
-  let indents = &segment[segment.trim_end().len()..];
-
-  let mut output = String::new();
-  for line in code.lines() {
-    // for each emitted line we copy the exact indentation of the last
-    // line from the surrounding code:
-    output.push_str(indents);
-    output.push_str(line.trim());
-    output.push('\n');
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Directive;
+
+  const FILE: &str = "class A {\n\tfunction f() {\n\t\tvar x: int;\n\t\tx = 1;\n\t}\n}\n";
+
+  fn emit(recipe: &str, file: &str, options: &EmitOptions) -> Emission {
+    let (_, directive) = Directive::parse(recipe).unwrap();
+
+    directive
+      .insert
+      .emit(file.to_owned(), &directive.code, options)
   }
 
-  output
+  fn emitted(emission: Emission) -> String {
+    match emission {
+      Emission::Emitted(file, _) => file,
+      _ => panic!("the code wasn't emitted")
+    }
+  }
+
+  #[test]
+  fn once_skips_the_code_it_already_emitted() {
+    let recipe = "@insert(\n  once(location)\n  at(function f)\n  below(var x)\n)\ny();";
+    let options = EmitOptions::default();
+
+    let file = emitted(emit(recipe, FILE, &options));
+    assert_eq!(file, FILE.replace("int;\n", "int;\n\t\ty();\n"));
+    assert!(matches!(
+      emit(recipe, &file, &options),
+      Emission::AlreadyPresent(_)
+    ));
+  }
+
+  #[test]
+  fn once_skips_a_select_that_was_already_replaced() {
+    let recipe = "@insert(\n  once(location)\n  at(function f)\n  select(x = 1;)\n)\nx = 2;";
+    let options = EmitOptions::default();
+
+    let file = emitted(emit(recipe, FILE, &options));
+    assert!(matches!(
+      emit(recipe, &file, &options),
+      Emission::AlreadyPresent(_)
+    ));
+
+    let other = FILE.replace("x = 1;", "x = 3;");
+    assert!(matches!(
+      emit(recipe, &other, &options),
+      Emission::NoLocation(_, Some(_))
+    ));
+  }
+
+  #[test]
+  fn once_skips_a_multiline_select_that_was_already_replaced() {
+    let recipe =
+      "@insert(\n  once(location)\n  select[[\n  var x: int;\n  x = 1;\n]]\n)\nvar x: int = 2;";
+    let options = EmitOptions::default();
+
+    let file = emitted(emit(recipe, FILE, &options));
+    assert!(file.contains("var x: int = 2;") && !file.contains("x = 1;"));
+    assert!(matches!(
+      emit(recipe, &file, &options),
+      Emission::AlreadyPresent(_)
+    ));
+  }
+
+  #[test]
+  fn once_recognizes_the_markers_of_the_mod() {
+    let recipe = "@insert(\n  once(location)\n  at(function f)\n  below(x = 1;)\n)\ny();";
+
+    // the code was moved away from the cursor, only its markers tell it's there
+    let file = FILE.replace(
+      "\t}\n}",
+      "\t}\n\n\tfunction g() {\n\t\t// cahirp: modA - BEGIN\n\t\ty();\n\t\t// cahirp: modA - END\n\t}\n}"
+    );
+
+    let options = EmitOptions {
+      module: "modA",
+      ..Default::default()
+    };
+    assert!(matches!(
+      emit(recipe, &file, &options),
+      Emission::AlreadyPresent(_)
+    ));
+
+    let options = EmitOptions {
+      module: "modB",
+      ..Default::default()
+    };
+    assert!(matches!(
+      emit(recipe, &file, &options),
+      Emission::Emitted(..)
+    ));
+  }
 }
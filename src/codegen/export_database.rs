@@ -1,36 +1,342 @@
 use std::collections::HashMap;
+use std::fmt::Display;
 
+use crate::error::CResult;
 use crate::parser::Directive;
 
+/// Separator between the mod name and the export name in a qualified `use`,
+/// for example `use("modCrow::CR4Player")`
+pub const NAMESPACE_SEPARATOR: &str = "::";
+
 #[derive(Debug)]
 pub struct ExportDatabase {
-  named_exports: HashMap<String, Directive>
+  named_exports: HashMap<ExportKey, Directive>
+}
+
+/// Maximum amount of nested `use` a directive can go through before its
+/// expansion is considered runaway.
+pub const MAX_USE_DEPTH: usize = 32;
+
+/// Exports are namespaced per mod so two mods can export the same name without
+/// overwriting each other.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct ExportKey {
+  pub module: String,
+  pub name: String
+}
+
+pub enum ExportLookupError {
+  NotFound,
+
+  /// The unqualified name isn't exported by the mod doing the lookup but by
+  /// more than one other mod.
+  Ambiguous(Vec<ExportKey>)
 }
 
 impl ExportDatabase {
   pub fn collect_named_exports(directives: &mut Vec<Directive>) -> Self {
     let exports: Vec<Directive> = directives
-      .extract_if(|d| d.parameters().has_export())
+      .extract_if(.., |d| d.parameters().has_export())
       .collect();
 
-    let named_exports =
-      HashMap::from_iter(exports.into_iter().filter_map(
-        |d| match d.parameters().exports_first() {
-          Some(key) => Some((key.to_owned(), d)),
-          None => None
-        }
-      ));
+    let mut named_exports = HashMap::new();
+    let mut duplicates = Vec::new();
+
+    for directive in exports {
+      let Some(name) = directive.parameters().exports_first() else {
+        continue;
+      };
+
+      let key = ExportKey {
+        module: directive.origin.module.clone(),
+        name: name.to_owned()
+      };
+
+      if let Err(e) = named_exports.try_insert(key, directive) {
+        duplicates.push((e.entry.key().clone(), e.entry.get().id, e.value.id));
+      }
+    }
+
+    for (key, kept, ignored) in duplicates {
+      crate::cli::prints::export_duplicate(&key, kept, ignored);
+    }
 
     Self { named_exports }
   }
 
-  pub fn feed_exports(&self, directives: &mut Vec<Directive>) {
-    for directive in directives {
-      directive.parameters_mut().feed_exports(self)
+  /// Expand the `use` parameters of the directives. A directive whose `use`
+  /// chain cycles or goes too deep is an error, while a `use` that can't be
+  /// resolved is only a warning unless `strict` is set. The first error stops
+  /// the build once everything was reported.
+  pub fn feed_exports(&self, directives: &mut Vec<Directive>, strict: bool) -> CResult<()> {
+    let mut first_error = None;
+
+    directives.retain_mut(|directive| {
+      let mut warnings = Vec::new();
+      let result = directive.feed_exports(self, &mut warnings);
+
+      for warning in warnings {
+        crate::cli::prints::use_error(directive, &warning, strict);
+
+        if strict {
+          first_error.get_or_insert(warning);
+        }
+      }
+
+      match result {
+        Ok(()) => true,
+        Err(e) => {
+          crate::cli::prints::use_error(directive, &e, true);
+          first_error.get_or_insert(e);
+
+          false
+        }
+      }
+    });
+
+    match first_error {
+      Some(e) => Err(e.into()),
+      None => Ok(())
+    }
+  }
+
+  pub fn exports(&self) -> impl Iterator<Item = (&ExportKey, &Directive)> {
+    self.named_exports.iter()
+  }
+
+  /// Find the export for the given `use` key:
+  /// - a qualified key (`modName::export`) only looks in the given mod
+  /// - an unqualified key looks in the mod doing the lookup first, then in the
+  ///   other mods as long as a single one of them exports the name.
+  pub fn get(
+    &self, key: &str, from_module: &str
+  ) -> Result<(&ExportKey, &Directive), ExportLookupError> {
+    if let Some((module, name)) = key.split_once(NAMESPACE_SEPARATOR) {
+      return self
+        .named_exports
+        .get_key_value(&ExportKey::new(module, name))
+        .ok_or(ExportLookupError::NotFound);
+    }
+
+    if let Some(export) = self
+      .named_exports
+      .get_key_value(&ExportKey::new(from_module, key))
+    {
+      return Ok(export);
+    }
+
+    let mut candidates: Vec<(&ExportKey, &Directive)> = self
+      .named_exports
+      .iter()
+      .filter(|(k, _)| k.name == key)
+      .collect();
+
+    match candidates.len() {
+      0 => Err(ExportLookupError::NotFound),
+      1 => Ok(candidates.remove(0)),
+      _ => {
+        let mut keys: Vec<ExportKey> = candidates.into_iter().map(|(k, _)| k.clone()).collect();
+        keys.sort_by(|a, b| a.module.cmp(&b.module));
+
+        Err(ExportLookupError::Ambiguous(keys))
+      }
     }
   }
+}
+
+#[derive(Debug)]
+pub enum UseError {
+  Unresolved {
+    module: String,
+    key: String
+  },
+  Ambiguous {
+    module: String,
+    key: String,
+    candidates: Vec<ExportKey>
+  },
+
+  /// The chain of exports that led back to an export already being expanded
+  Cycle(Vec<ExportKey>),
+
+  /// The chain of exports that exceeded [MAX_USE_DEPTH]
+  TooDeep(Vec<ExportKey>)
+}
+
+impl Display for UseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let chain = |keys: &Vec<ExportKey>| {
+      keys
+        .iter()
+        .map(ExportKey::to_string)
+        .collect::<Vec<_>>()
+        .join(" -> ")
+    };
+
+    match self {
+      UseError::Unresolved { module, key } => {
+        write!(f, "use({key}) from {module} matches no export")
+      }
+      UseError::Ambiguous {
+        module,
+        key,
+        candidates
+      } => write!(
+        f,
+        "use({key}) from {module} matches exports from multiple mods: {}",
+        chain(candidates).replace(" -> ", ", ")
+      ),
+      UseError::Cycle(keys) => write!(f, "use cycle: {}", chain(keys)),
+      UseError::TooDeep(keys) => write!(f, "use chain deeper than {MAX_USE_DEPTH}: {}", chain(keys))
+    }
+  }
+}
+
+impl ExportKey {
+  pub fn new(module: &str, name: &str) -> Self {
+    Self {
+      module: module.to_owned(),
+      name: name.to_owned()
+    }
+  }
+}
+
+impl Display for ExportKey {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}{NAMESPACE_SEPARATOR}{}", self.module, self.name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::CError;
+
+  fn directive(module: &str, parameters: &[&str]) -> Directive {
+    let recipe = format!("@insert(\n{}\n)\ncode();", parameters.join("\n"));
+    let (_, mut directive) = Directive::parse(&recipe).unwrap();
+    directive.origin.module = module.to_owned();
+
+    directive
+  }
+
+  fn database(directives: &mut Vec<Directive>) -> ExportDatabase {
+    ExportDatabase::collect_named_exports(directives)
+  }
+
+  fn ats(directive: &Directive) -> Vec<&str> {
+    directive.parameters().ats().collect()
+  }
+
+  #[test]
+  fn exports_are_taken_out_of_the_directives() {
+    let mut directives = vec![
+      directive("modA", &["export(player)", "at(class CR4Player)"]),
+      directive("modA", &["use(player)", "at(function f)"]),
+      directive("modA", &["export(player)", "at(class Duplicate)"]),
+    ];
+    let export_db = database(&mut directives);
+
+    assert_eq!(directives.len(), 1);
+    assert_eq!(export_db.exports().count(), 1);
+
+    // the first export of a name is kept
+    let (_, export) = export_db.get("player", "modA").ok().unwrap();
+    assert_eq!(ats(export), ["class CR4Player"]);
+  }
+
+  #[test]
+  fn lookups_are_namespaced_per_mod() {
+    let mut directives = vec![
+      directive("modA", &["export(player)", "at(class A)"]),
+      directive("modB", &["export(player)", "at(class B)"]),
+      directive("modB", &["export(inventory)", "at(class Inventory)"]),
+    ];
+    let export_db = database(&mut directives);
+
+    let found =
+      |key: &str, module: &str| export_db.get(key, module).ok().map(|(k, _)| k.to_string());
+
+    assert_eq!(found("player", "modA").as_deref(), Some("modA::player"));
+    assert_eq!(
+      found("modB::player", "modA").as_deref(),
+      Some("modB::player")
+    );
+    assert_eq!(
+      found("inventory", "modA").as_deref(),
+      Some("modB::inventory")
+    );
+    assert!(matches!(
+      export_db.get("modA::inventory", "modA"),
+      Err(ExportLookupError::NotFound)
+    ));
+    assert!(
+      matches!(export_db.get("player", "modC"), Err(ExportLookupError::Ambiguous(keys)) if keys.len() == 2)
+    );
+  }
+
+  #[test]
+  fn uses_are_replaced_by_the_parameters_of_their_export() {
+    let mut directives = vec![
+      directive("modA", &["export(player)", "at(class CR4Player)"]),
+      directive(
+        "modA",
+        &["export(repair)", "use(player)", "at(function Repair)"]
+      ),
+      directive("modB", &["use(modA::repair)", "below(var x)"]),
+    ];
+    let export_db = database(&mut directives);
+    export_db.feed_exports(&mut directives, true).ok().unwrap();
+
+    assert_eq!(ats(&directives[0]), ["class CR4Player", "function Repair"]);
+  }
+
+  #[test]
+  fn unresolved_uses_only_fail_a_strict_build() {
+    let mut directives = vec![directive("modA", &["use(nothing)", "at(class A)"])];
+    let export_db = database(&mut directives);
+
+    assert!(export_db.feed_exports(&mut directives, false).is_ok());
+    assert_eq!(directives.len(), 1);
+    assert!(export_db.feed_exports(&mut directives, true).is_err());
+  }
+
+  #[test]
+  fn use_cycles_fail_the_build() {
+    let mut directives = vec![
+      directive("modA", &["export(a)", "use(b)"]),
+      directive("modA", &["export(b)", "use(a)"]),
+      directive("modA", &["use(a)", "at(class A)"]),
+      directive("modA", &["at(class B)"]),
+    ];
+    let export_db = database(&mut directives);
+
+    // a cycle is an error even without --strict
+    assert!(matches!(
+      export_db.feed_exports(&mut directives, false),
+      Err(CError::Use(UseError::Cycle(_)))
+    ));
+    assert_eq!(directives.len(), 1);
+    assert_eq!(ats(&directives[0]), ["class B"]);
+  }
+
+  #[test]
+  fn chains_that_go_too_deep_fail_the_build() {
+    let exports: Vec<(String, String)> = (0..=MAX_USE_DEPTH)
+      .map(|i| (format!("export(e{i})"), format!("use(e{})", i + 1)))
+      .collect();
+
+    let mut directives: Vec<Directive> = exports
+      .iter()
+      .map(|(export, next)| directive("modA", &[export, next]))
+      .collect();
+    directives.push(directive("modA", &["use(e0)", "at(class A)"]));
+    let export_db = database(&mut directives);
 
-  pub fn get(&self, key: &str) -> Option<&Directive> {
-    self.named_exports.get(key)
+    assert!(matches!(
+      export_db.feed_exports(&mut directives, false),
+      Err(CError::Use(UseError::TooDeep(_)))
+    ));
+    assert!(directives.is_empty());
   }
 }
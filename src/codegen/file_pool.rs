@@ -1,202 +1,701 @@
 use std::cell::Cell;
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use rayon::prelude::*;
 
 use crate::cli::prints::verbose_debug;
-use crate::encoding::read_file;
+use crate::encoding::{encode, read_script, unrepresentable, TextEncoding, TextFormat};
 use crate::error::CResult;
-use crate::game::paths;
-use crate::parser::Directive;
+use crate::game::index::ScriptIndex;
+use crate::game::settings::ModSettings;
+use crate::game::{glob, paths};
+use crate::parser::{Directive, DirectiveId, Parameter, AUTO_FILE};
 
-use super::{CodeEmitter, ExecutionOrchestrator, ExportDatabase};
+use super::{
+  conflicts, unmet_conditions, CodeCursor, CodeEmitter, Conflict, Emission, EmitOptions,
+  ExecutionOrchestrator, MarkerStyle, Miss, OnceMode, SourceFile, SourceMap
+};
 
-type FileLockMap = HashMap<PathBuf, Arc<Mutex<Cell<String>>>>;
+type FileLockMap = HashMap<PathBuf, Arc<Mutex<Cell<SourceFile>>>>;
+
+/// A script read from the trees: its path in the output folder, where it comes
+/// from, its contents and its format
+type FoundFile = (PathBuf, FileOrigin, String, TextFormat);
 
 /// A thread-safe pool that holds the content of the files in memory and ensures
 /// only one thread has access to a given file at once. Allowing to throw any
 /// amount of threads at it so the work is spread without fearing data races.
 pub struct FilePool {
   directives: Vec<Directive>,
-  export_db: ExportDatabase,
 
   /// The file locks is what  ensure only a single thread has access to the
   /// underlying Cell to mutate the content of the "in-memory file"
-  file_locks: FileLockMap
+  file_locks: FileLockMap,
+
+  /// The files each directive works on, with its `file()` patterns expanded
+  targets: HashMap<DirectiveId, Vec<FileTarget>>,
+
+  /// The files named by each directive that don't exist, or that couldn't be
+  /// located, along with the parameter that named or located them
+  unresolved: HashMap<DirectiveId, Vec<(PathBuf, Miss)>>,
+
+  /// The directives whose cursor movements are printed as they emit code
+  traced: HashSet<DirectiveId>
+}
+
+/// A file a directive works on
+#[derive(Debug, Clone)]
+struct FileTarget {
+  suffix: PathBuf,
+
+  /// Whether the file was matched by a `file()` pattern rather than named, in
+  /// which case finding no location in it is expected
+  from_pattern: bool
 }
 
 impl FilePool {
   /// At creation the [FilePool] generates the exhaustive flat list of files that
   /// directives will work on.
+  ///
+  /// `skip_output` ignores the files already in the output folder, as if it was
+  /// cleaned before the build.
+  ///
+  /// A directive whose file can't be located is reported and doesn't run,
+  /// unless `strict` is set in which case the first error stops the build once
+  /// everything was reported.
   pub fn new(
-    directives: Vec<Directive>, export_db: ExportDatabase, game_root: &PathBuf, out: &PathBuf,
-    search_behaviour: FileSearchBehaviour
+    directives: Vec<Directive>, game_root: &Path, out: &Path,
+    search_behaviour: FileSearchBehaviour, skip_output: bool, mod_settings: &ModSettings,
+    strict: bool
   ) -> CResult<Self> {
     let mut locks = HashMap::new();
-    let mods = paths::mod_folders(game_root, out)?;
+    let mut targets = HashMap::new();
+    let mut unresolved: HashMap<DirectiveId, Vec<(PathBuf, Miss)>> = HashMap::new();
+    let trees = ScriptTrees::new(game_root, out, mod_settings)?;
+
+    // the files the patterns are matched against, only listed if a directive
+    // uses a pattern or needs its file to be located
+    let mut candidates = None;
+    let mut scripts = None;
+    let mut first_error = None;
 
     // fill the locks so each file has a corresponding lock
     for directive in &directives {
-      let suffixes = directive.file_suffixes();
-      for suffix in suffixes {
-        let search_result =
-          Self::find_file(&locks, game_root, &out, &suffix, &mods, search_behaviour);
+      let mut directive_targets: Vec<FileTarget> = Vec::new();
 
-        match search_result {
-          FileSearchResult::AlreadyInCache(_) => {}
-          FileSearchResult::File((cahirp_path, contents)) => {
-            locks.insert(cahirp_path, Arc::new(Mutex::new(Cell::new(contents))));
+      let mut located = None;
+      if directive.parameters().locates_file() {
+        let scripts = scripts.get_or_insert_with(|| {
+          let candidates = candidates.get_or_insert_with(|| trees.candidates(search_behaviour));
+
+          Self::read_scripts(candidates, &trees, out, search_behaviour, skip_output)
+        });
+
+        match Self::locate_file(directive, scripts) {
+          Ok(suffix) => located = Some(suffix),
+          Err(e) => {
+            crate::cli::prints::file_not_located(directive, &e, strict);
+
+            let miss = Self::locate_miss(directive);
+            unresolved
+              .entry(directive.id)
+              .or_default()
+              .push((PathBuf::from(AUTO_FILE), miss));
+            first_error.get_or_insert(e);
           }
-          FileSearchResult::NotFound => {
-            println!("Could not find with name [{:?}]", suffix);
+        }
+      }
+
+      for file in directive.file_suffixes().chain(located) {
+        let pattern = file.to_string_lossy();
+        let from_pattern = glob::is_pattern(&pattern);
+        let found: Vec<PathBuf> = match from_pattern {
+          true => {
+            let candidates = candidates.get_or_insert_with(|| trees.candidates(search_behaviour));
+
+            let matched: Vec<PathBuf> = candidates
+              .iter()
+              .filter(|suffix| glob::matches(&pattern, &suffix.to_string_lossy()))
+              .cloned()
+              .collect();
+
+            if matched.is_empty() {
+              crate::cli::prints::file_pattern_no_match(directive, &pattern);
+            }
+
+            matched
+          }
+          false => vec![file.clone()]
+        };
+
+        for suffix in found {
+          // the same file may be written with different cases in the recipes
+          let suffix = trees.canonical(&suffix);
+
+          // the scripts read to locate the files are reused rather than read
+          // again
+          let search_result = match scripts.as_ref().and_then(|s| s.get(&suffix)) {
+            Some((path, ..)) if locks.contains_key(path) => FileSearchResult::AlreadyInCache,
+            Some(file) => FileSearchResult::File(file.clone()),
+            None => Self::find_file(&locks, &trees, out, &suffix, search_behaviour, skip_output)
+          };
+
+          match search_result {
+            FileSearchResult::AlreadyInCache => {}
+            FileSearchResult::File((cahirp_path, origin, contents, format)) => {
+              if format.is_guessed() {
+                let file = format!("{} from {origin}", suffix.display());
+                crate::cli::prints::encoding_guessed(&file, format.encoding, true);
+              }
+
+              locks.insert(
+                cahirp_path,
+                Arc::new(Mutex::new(Cell::new(SourceFile::new(
+                  contents, origin, format
+                ))))
+              );
+            }
+            FileSearchResult::NotFound => {
+              println!("Could not find with name [{:?}]", suffix);
+
+              let miss = Self::file_miss(directive, &file);
+              unresolved
+                .entry(directive.id)
+                .or_default()
+                .push((suffix, miss));
+              continue;
+            }
+          }
+
+          match directive_targets.iter_mut().find(|t| t.suffix == suffix) {
+            // a file that is both named and matched by a pattern is a named one
+            Some(target) => target.from_pattern &= from_pattern,
+            None => directive_targets.push(FileTarget {
+              suffix,
+              from_pattern
+            })
           }
         }
       }
+
+      targets.insert(directive.id, directive_targets);
+    }
+
+    if let Some(e) = first_error.filter(|_| strict) {
+      return Err(e.into());
     }
 
     Ok(Self {
       file_locks: locks,
       directives,
-      export_db
+      targets,
+      unresolved,
+      traced: HashSet::new()
     })
   }
 
+  /// The `file()` parameter of the directive that named a file that doesn't
+  /// exist, as the parameter the directive got lost on
+  fn file_miss(directive: &Directive, file: &Path) -> Miss {
+    let named = directive
+      .parameters()
+      .with_origins()
+      .find(|(parameter, _)| matches!(parameter, Parameter::File(name) if Path::new(name) == file));
+
+    match named {
+      Some((parameter, origin)) => Miss {
+        parameter: parameter.clone(),
+        origin: origin.cloned()
+      },
+      None => Miss {
+        parameter: Parameter::File(file.to_string_lossy().into_owned()),
+        origin: None
+      }
+    }
+  }
+
+  /// The parameter a directive whose file couldn't be located got lost on, its
+  /// `file(auto)` or else its first anchor
+  fn locate_miss(directive: &Directive) -> Miss {
+    let parameters = directive.parameters();
+    let anchor = parameters.first_anchor();
+
+    let located_by = parameters
+      .with_origins()
+      .find(|(parameter, _)| match parameter {
+        Parameter::File(file) => file == AUTO_FILE,
+        Parameter::At(s)
+        | Parameter::Below(s)
+        | Parameter::Above(s)
+        | Parameter::After(s)
+        | Parameter::Before(s)
+        | Parameter::Select(s) => Some(s.as_str()) == anchor,
+        Parameter::MultilineSelect(_) => anchor.is_some(),
+        _ => false
+      });
+
+    match located_by {
+      Some((parameter, origin)) => Miss {
+        parameter: parameter.clone(),
+        origin: origin.cloned()
+      },
+      None => Miss {
+        parameter: Parameter::File(AUTO_FILE.to_owned()),
+        origin: None
+      }
+    }
+  }
+
+  /// The files the directive works on
+  fn targets(&self, directive: &Directive) -> &[FileTarget] {
+    self
+      .targets
+      .get(&directive.id)
+      .map(Vec::as_slice)
+      .unwrap_or_default()
+  }
+
+  /// Write every file in the given encoding rather than in the encoding of the
+  /// file it is based on, the line endings are kept.
+  pub fn force_encoding(self, encoding: Option<TextEncoding>) -> Self {
+    if let Some(encoding) = encoding {
+      for file in self.file_locks.values() {
+        let cell = file.lock().expect("mutex poisoning error");
+        let mut contents = cell.take();
+        contents.format = contents.format.with_encoding(encoding);
+        cell.set(contents);
+      }
+    }
+
+    self
+  }
+
+  /// Print every step of the cursor of the given directives when they emit
+  /// code, along with the code around it.
+  pub fn trace(mut self, directives: HashSet<DirectiveId>) -> Self {
+    self.traced = directives;
+    self
+  }
+
   /// Generate code and mutate the inner "in-memory" file locks with the results
   ///
+  /// `atomic` makes the directives all-or-nothing, see [`emit_atomic()`]
+  ///
   /// If persistence to disk is needed then refer to the [`persist()`] method
-  pub fn emit(self, out: &PathBuf, mod_names: &Vec<String>) -> std::io::Result<Self> {
-    // the initial variables are the names of all the mods that are installed,
-    // with a special prefix to clearly indicate these are the installed mods.
-    let initial_variables: Vec<String> =
-      mod_names.iter().map(|s| format!("installed.{s}")).collect();
-
-    let mut variables = HashSet::from_iter(initial_variables.iter().map(std::ops::Deref::deref));
-    let mut orchestrator = ExecutionOrchestrator::new(&self.directives, &variables);
-
-    loop {
-      if orchestrator.finished {
-        if crate::VERBOSE {
-          verbose_debug("directive processing queue empty".to_owned());
-        }
+  pub fn emit(
+    mut self, out: &Path, mod_names: &[String], markers: MarkerStyle, once: Option<OnceMode>,
+    atomic: bool
+  ) -> std::io::Result<Self> {
+    let installed = ExecutionOrchestrator::installed_variables(mod_names);
+    let conflicts = Mutex::new(Vec::new());
 
-        break;
-      }
+    let (skipped, variables) =
+      ExecutionOrchestrator::run(&self.directives, &installed, |to_run, _| {
+        independent_groups(to_run, &self.targets)
+          .par_iter()
+          .for_each(|group| {
+            for directive in group {
+              let markers = markers.markers(directive);
+              let options = EmitOptions {
+                markers: markers.as_ref(),
+                once,
+                module: &directive.origin.module,
+                id: directive.id
+              };
+
+              let found = match atomic {
+                true => self.emit_atomic(out, directive, &options),
+                false => self.emit_each(out, directive, &options)
+              };
+
+              if !found.is_empty() {
+                let mut conflicts = conflicts.lock().expect("mutex poisoning error");
 
-      orchestrator.to_run.par_iter().for_each(|directive| {
-        for suffix in directive.file_suffixes() {
-          let arc = self.file_lock(out, &suffix);
-          let cell = arc.lock().expect("mutex poisoning error");
-          let contents = cell.take();
-          let new_contents = match directive
-            .insert
-            .emit(contents, &directive.code, &self.export_db)
-          {
-            Ok(s) => s,
-            Err(s) => {
-              crate::cli::prints::build_no_location_found(out, directive.insert.parameters());
-
-              s
+                conflicts.extend(found);
+              }
             }
-          };
+          });
+      });
+
+    // the files a pattern matched but that no directive changed are not part of
+    // the output, a selection replaced by nothing leaves no span but still
+    // changes the file
+    let named: HashSet<PathBuf> = self
+      .targets
+      .values()
+      .flatten()
+      .filter(|target| !target.from_pattern)
+      .map(|target| out.join(&target.suffix))
+      .collect();
 
-          cell.set(new_contents);
+    self.file_locks.retain(|path, file| {
+      let cell = file.lock().expect("mutex poisoning error");
+      let contents = cell.take();
+      let edited = contents.contents != contents.base;
+      cell.set(contents);
+
+      edited || named.contains(path)
+    });
+
+    // whatever is left to skip once there is nothing left to run will never
+    // run, this is reported as it is most likely a typo in a variable
+    let never_ran: Vec<_> = skipped
+      .into_iter()
+      .map(|directive| (directive, unmet_conditions(directive, &variables)))
+      .collect();
+
+    if !never_ran.is_empty() {
+      crate::cli::prints::never_ran(&never_ran);
+    }
+
+    // the conflicts of a file are found in a stable order but the files are
+    // edited in parallel
+    let mut conflicts = conflicts.into_inner().expect("mutex poisoning error");
+    conflicts.sort_by(|a, b| a.file.cmp(&b.file));
+
+    if !conflicts.is_empty() {
+      let directives: HashMap<DirectiveId, &Directive> =
+        self.directives.iter().map(|d| (d.id, d)).collect();
+
+      crate::cli::prints::conflicts(&conflicts::group_by_mods(&conflicts, &directives));
+    }
+
+    Ok(self)
+  }
+
+  /// Emit the directive in each of its files one after the other, a file where
+  /// no location is found is left untouched while the others are still edited.
+  fn emit_each(&self, out: &Path, directive: &Directive, options: &EmitOptions) -> Vec<Conflict> {
+    let mut found = Vec::new();
+
+    for target in self.targets(directive) {
+      let arc = self.file_lock(out, &target.suffix);
+      let cell = arc.lock().expect("mutex poisoning error");
+      let mut file = cell.take();
+
+      match self.emit_in_file(directive, target, &mut file, options) {
+        Ok(conflicts) => found.extend(conflicts),
+        Err(_) if target.from_pattern => skipped_pattern_match(&target.suffix, directive),
+        Err(miss) => {
+          crate::cli::prints::build_no_location_found(&target.suffix, directive, miss.as_ref())
         }
-      });
+      }
 
-      for &dir in &orchestrator.to_run {
-        for define in dir.parameters().defines() {
-          if crate::VERBOSE {
-            verbose_debug(format!("define({define})"));
-          }
+      cell.set(file);
+    }
+
+    found
+  }
+
+  /// Emit the directive in all of its files or in none of them. The edits are
+  /// staged on copies of the files and only committed once every file found a
+  /// location, otherwise they're all discarded and the rollback is reported.
+  ///
+  /// The files matched by a pattern aren't required to have a location, while
+  /// a named file that doesn't exist is a miss like a missing location.
+  fn emit_atomic(&self, out: &Path, directive: &Directive, options: &EmitOptions) -> Vec<Conflict> {
+    let mut targets = self.targets(directive).to_vec();
+    targets.sort_by(|a, b| a.suffix.cmp(&b.suffix));
+
+    let suffixes: Vec<PathBuf> = targets.iter().map(|t| t.suffix.clone()).collect();
+
+    let unresolved = self
+      .unresolved
+      .get(&directive.id)
+      .map(Vec::as_slice)
+      .unwrap_or_default();
+    if let Some((suffix, miss)) = unresolved.first() {
+      crate::cli::prints::build_rolled_back(directive, suffix, &suffixes, Some(miss));
 
-          variables.insert(define);
+      return Vec::new();
+    }
+
+    // the files are locked in the same order by every directive so two of them
+    // can't wait on each other, and the locks are held until the edits are
+    // committed so no other directive sees a half-applied directive.
+    let arcs: Vec<_> = suffixes.iter().map(|s| self.file_lock(out, s)).collect();
+    let cells: Vec<_> = arcs
+      .iter()
+      .map(|arc| arc.lock().expect("mutex poisoning error"))
+      .collect();
+
+    let mut staged = Vec::with_capacity(cells.len());
+    let mut found = Vec::new();
+
+    for (target, cell) in targets.iter().zip(&cells) {
+      let suffix = &target.suffix;
+      let file = cell.take();
+      let mut staging = file.clone();
+      cell.set(file);
+
+      match self.emit_in_file(directive, target, &mut staging, options) {
+        Ok(conflicts) => {
+          found.extend(conflicts);
+          staged.push(staging);
+        }
+        Err(_) if target.from_pattern => {
+          skipped_pattern_match(suffix, directive);
+          staged.push(staging);
+        }
+        Err(miss) => {
+          crate::cli::prints::build_rolled_back(directive, suffix, &suffixes, miss.as_ref());
+
+          return Vec::new();
         }
       }
+    }
 
-      orchestrator.next(&variables);
+    for (cell, file) in cells.iter().zip(staged) {
+      cell.set(file);
     }
 
-    Ok(self)
+    found
+  }
+
+  /// Emit the code of the directive in a single file and return the conflicts
+  /// it caused, or the [Miss] of the cursor if no location was found in which
+  /// case the file is left untouched.
+  fn emit_in_file(
+    &self, directive: &Directive, target: &FileTarget, file: &mut SourceFile, options: &EmitOptions
+  ) -> Result<Vec<Conflict>, Option<Miss>> {
+    let suffix = &target.suffix;
+    let contents = std::mem::take(&mut file.contents);
+
+    if self.traced.contains(&directive.id) {
+      let cursor = CodeCursor::traced(directive.parameters(), &contents);
+
+      // the files of a pattern without the anchors would only bury the others
+      if !target.from_pattern || cursor.missed.is_none() {
+        crate::cli::prints::explain_cursor(directive, suffix, &contents, &cursor);
+      }
+    }
+
+    match directive.insert.emit(contents, &directive.code, options) {
+      Emission::Emitted(contents, edit) => {
+        let found = file.apply(contents, edit, directive.id, &directive.code);
+
+        Ok(
+          found
+            .into_iter()
+            .map(|(kind, first)| Conflict {
+              file: suffix.clone(),
+              kind,
+              first,
+              second: directive.id
+            })
+            .collect()
+        )
+      }
+      Emission::AlreadyPresent(contents) => {
+        crate::cli::prints::build_already_present(suffix, directive);
+        file.contents = contents;
+
+        Ok(Vec::new())
+      }
+      Emission::NoLocation(contents, miss) => {
+        file.contents = contents;
+
+        Err(miss)
+      }
+    }
   }
 
-  /// Persist the content of the in-memory files to disk
-  pub fn persist(self) -> std::io::Result<()> {
-    let results: Vec<std::io::Result<()>> = self
+  /// Persist the content of the in-memory files to disk, along with the
+  /// [SourceMap] of the generated code.
+  pub fn persist(self, out: &Path) -> std::io::Result<()> {
+    let directives: HashMap<DirectiveId, &Directive> =
+      self.directives.iter().map(|d| (d.id, d)).collect();
+
+    let results: Vec<std::io::Result<(PathBuf, SourceFile)>> = self
       .file_locks
       .into_par_iter()
-      .map(|(path, contents)| {
+      .map(|(path, file)| {
         if let Some(parent) = path.parent() {
-          if let Err(_) = std::fs::create_dir_all(parent) {}
+          std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = file.lock().expect("mutex poisoning error").take();
+
+        // a file Windows-1252 can't hold is written in UTF-16 rather than
+        // losing the characters, the game reads both
+        let lost = unrepresentable(&file.contents, file.format.encoding);
+        if !lost.is_empty() {
+          let suffix = path.strip_prefix(out).unwrap_or(&path);
+          let fallback = file.format.with_encoding(TextEncoding::Utf16le);
+          crate::cli::prints::encoding_unrepresentable(
+            suffix,
+            file.format.encoding,
+            fallback.encoding,
+            &lost
+          );
+
+          file.format = fallback;
         }
 
-        let contents = contents.lock().expect("mutex poisoning error").take();
+        std::fs::write(&path, encode(&file.contents, file.format))?;
 
-        std::fs::write(path, contents)
+        Ok((path, file))
       })
       .collect();
 
+    // the source map must describe what is on disk, so a file that couldn't be
+    // written fails the build rather than leaving a stale map behind
+    let mut source_map = SourceMap::default();
     for result in results {
-      if let Err(e) = result {
-        println!("error while writing output to disk: {e}");
-      }
+      let (path, file) = result?;
+      let suffix = path.strip_prefix(out).unwrap_or(&path).to_path_buf();
+
+      source_map.add_file(suffix, &file, &directives);
+    }
+
+    if out.exists() {
+      source_map.write(out)?;
     }
 
     Ok(())
   }
 
+  /// Take the in-memory files out of the pool, keyed by their path relative to
+  /// the output folder and sorted by it.
+  pub fn into_files(self, out: &Path) -> Vec<(PathBuf, SourceFile)> {
+    let mut files: Vec<(PathBuf, SourceFile)> = self
+      .file_locks
+      .into_iter()
+      .map(|(path, file)| {
+        let suffix = path.strip_prefix(out).unwrap_or(&path).to_path_buf();
+        let file = file.lock().expect("mutex poisoning error").take();
+
+        (suffix, file)
+      })
+      .collect();
+
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    files
+  }
+
+  /// Generate a unified diff for each file between its base content and the
+  /// generated content, without writing anything to disk.
+  pub fn diff(self, out: &Path) -> String {
+    let files = self.into_files(out);
+
+    let mut output = String::new();
+    for (suffix, file) in files {
+      let suffix = suffix.to_string_lossy().replace('\\', "/");
+      let diff = similar::TextDiff::from_lines(&file.base, &file.contents);
+
+      output.push_str(
+        &diff
+          .unified_diff()
+          .header(
+            &format!("a/{suffix}\t({})", file.origin),
+            &format!("b/{suffix}\t(cahirp)")
+          )
+          .to_string()
+      );
+    }
+
+    output
+  }
+
   fn find_file(
-    locks: &FileLockMap, game_root: &PathBuf, out: &PathBuf, file_suffix: &PathBuf,
-    mod_folders: &Vec<PathBuf>, search_behaviour: FileSearchBehaviour
+    locks: &FileLockMap, trees: &ScriptTrees, out: &Path, file_suffix: &Path,
+    search_behaviour: FileSearchBehaviour, skip_output: bool
   ) -> FileSearchResult {
-    fn find_merge_file(game_root: &PathBuf, file_suffix: &PathBuf) -> Option<String> {
-      read_file(&paths::merge_scripts(game_root).join(file_suffix)).ok()
+    let read = |tree: &ScriptIndex| {
+      tree
+        .path(file_suffix)
+        .and_then(|path| read_script(&path).ok())
+    };
+
+    // the output is written with the casing of the game whatever the casing of
+    // the recipes and of the mods
+    let cahirp_file = out.join(trees.canonical(file_suffix));
+
+    if locks.contains_key(&cahirp_file) {
+      return FileSearchResult::AlreadyInCache;
     }
 
-    /// Find a file inside mod folders, this can happen when a file is edited
-    /// by a single mod which doesn't need any merging.
-    fn find_mod_file(file_suffix: &PathBuf, mod_folders: &Vec<PathBuf>) -> Option<String> {
-      for module in mod_folders {
-        let p = module.join(file_suffix);
+    let search = match skip_output {
+      true => None,
+      false => read(&trees.output).map(|f| (FileOrigin::Output, f))
+    };
 
-        if let Ok(content) = read_file(&p) {
-          return Some(content);
-        }
-      }
+    let search = match search_behaviour {
+      // if allowed, search for merge & mod files first. A file can be in a mod
+      // when it is edited by a single mod which doesn't need any merging.
+      FileSearchBehaviour::Content0AndMods => search
+        .or_else(|| read(&trees.merged).map(|f| (FileOrigin::MergedFiles, f)))
+        .or_else(|| {
+          trees.mods.iter().find_map(|module| {
+            read(module).map(|f| (FileOrigin::from_mod_scripts(module.root()), f))
+          })
+        }),
+      FileSearchBehaviour::Content0 => search
+    };
 
-      None
+    // finally look at the content0 files
+    match search.or_else(|| read(&trees.content0).map(|f| (FileOrigin::Content0, f))) {
+      Some((origin, (s, format))) => FileSearchResult::File((cahirp_file, origin, s, format)),
+      None => FileSearchResult::NotFound
     }
+  }
 
-    fn find_content_file(game_root: &PathBuf, file_suffix: &PathBuf) -> Option<String> {
-      read_file(&paths::content_scripts(game_root).join(file_suffix)).ok()
-    }
+  /// Read every candidate file like the build would, so the anchors of the
+  /// directives are searched in the code they will actually work on. The files
+  /// are keyed by their suffix.
+  fn read_scripts(
+    candidates: &[PathBuf], trees: &ScriptTrees, out: &Path, search_behaviour: FileSearchBehaviour,
+    skip_output: bool
+  ) -> BTreeMap<PathBuf, FoundFile> {
+    let no_locks = HashMap::new();
 
-    let cahirp_file = out.join(file_suffix);
+    candidates
+      .par_iter()
+      .filter_map(|suffix| {
+        let search_result =
+          Self::find_file(&no_locks, trees, out, suffix, search_behaviour, skip_output);
 
-    if locks.contains_key(&cahirp_file) {
-      FileSearchResult::AlreadyInCache(cahirp_file)
-    } else {
-      let search = read_file(&cahirp_file).ok();
-
-      let search = match search_behaviour {
-        // if allowed, search for merge & mod files first
-        FileSearchBehaviour::Content0AndMods => search
-          .or_else(|| find_merge_file(game_root, file_suffix))
-          .or_else(|| find_mod_file(file_suffix, mod_folders)),
-        FileSearchBehaviour::Content0 => search
-      };
-
-      // finally look at the content0 files
-      match search.or_else(|| find_content_file(game_root, file_suffix)) {
-        Some(s) => FileSearchResult::File((cahirp_file, s)),
-        None => FileSearchResult::NotFound
-      }
+        match search_result {
+          FileSearchResult::File(file) => Some((suffix.clone(), file)),
+          _ => None
+        }
+      })
+      .collect()
+  }
+
+  /// Find the file of a directive without a `file` or with `file(auto)`: the
+  /// only script that contains its first anchor.
+  fn locate_file(
+    directive: &Directive, scripts: &BTreeMap<PathBuf, FoundFile>
+  ) -> Result<PathBuf, LocateError> {
+    let Some(anchor) = directive.parameters().first_anchor() else {
+      return Err(LocateError::NoAnchor);
+    };
+
+    let mut found: Vec<PathBuf> = scripts
+      .iter()
+      .filter(|(_, (_, _, contents, _))| contents.contains(anchor))
+      .map(|(suffix, _)| suffix.clone())
+      .collect();
+
+    match found.len() {
+      0 => return Err(LocateError::NotFound(anchor.to_owned())),
+      1 => {}
+      _ => return Err(LocateError::Ambiguous(anchor.to_owned(), found))
     }
+
+    let suffix = found.remove(0);
+    if crate::VERBOSE {
+      verbose_debug(format!("{directive} located in {}", suffix.display()));
+    }
+
+    Ok(suffix)
   }
 
   /// Get the file mutex for the given file suffix
-  pub fn file_lock(&self, out: &PathBuf, file_suffix: &PathBuf) -> Arc<Mutex<Cell<String>>> {
+  pub fn file_lock(&self, out: &Path, file_suffix: &Path) -> Arc<Mutex<Cell<SourceFile>>> {
     let path = out.join(file_suffix);
 
     Arc::clone(
@@ -208,14 +707,447 @@ impl FilePool {
   }
 }
 
+/// A file matched by a `file()` pattern without the anchors of the directive is
+/// expected, it is only mentioned in verbose mode.
+fn skipped_pattern_match(suffix: &Path, directive: &Directive) {
+  if crate::VERBOSE {
+    verbose_debug(format!(
+      "{directive} skipped {}, no location found",
+      suffix.display()
+    ));
+  }
+}
+
+/// Split the directives into groups that share no file. The groups emit their
+/// code in parallel while the directives of a group emit theirs one after the
+/// other, in the order of the recipes, so a file is edited in the same order on
+/// every build.
+fn independent_groups<'a>(
+  directives: &[&'a Directive], targets: &HashMap<DirectiveId, Vec<FileTarget>>
+) -> Vec<Vec<&'a Directive>> {
+  let mut directives = directives.to_vec();
+  directives.sort_by(|a, b| a.source_order().cmp(&b.source_order()));
+
+  // a union-find where each directive points to a directive it shares a file
+  // with, until the first directive of the group
+  let mut parents: Vec<usize> = (0..directives.len()).collect();
+  let mut first_editor: HashMap<PathBuf, usize> = HashMap::new();
+
+  fn root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+      parents[i] = parents[parents[i]];
+      i = parents[i];
+    }
+
+    i
+  }
+
+  for (i, directive) in directives.iter().enumerate() {
+    for target in targets.get(&directive.id).into_iter().flatten() {
+      let suffix = target.suffix.clone();
+      match first_editor.get(&suffix) {
+        Some(&other) => {
+          let (a, b) = (root(&mut parents, i), root(&mut parents, other));
+          parents[a.max(b)] = a.min(b);
+        }
+        None => {
+          first_editor.insert(suffix, i);
+        }
+      }
+    }
+  }
+
+  let mut groups: BTreeMap<usize, Vec<&Directive>> = BTreeMap::new();
+  for (i, directive) in directives.into_iter().enumerate() {
+    groups
+      .entry(root(&mut parents, i))
+      .or_default()
+      .push(directive);
+  }
+
+  groups.into_values().collect()
+}
+
 enum FileSearchResult {
-  AlreadyInCache(PathBuf),
-  File((PathBuf, String)),
+  AlreadyInCache,
+  File(FoundFile),
   NotFound
 }
 
+/// Why the file of a directive without a `file()`, or with `file(auto)`,
+/// couldn't be located
+#[derive(Debug)]
+pub enum LocateError {
+  /// The directive has no anchor to look for
+  NoAnchor,
+
+  /// No script contains the anchor
+  NotFound(String),
+
+  /// Several scripts contain the anchor, they're the candidates
+  Ambiguous(String, Vec<PathBuf>)
+}
+
+impl std::fmt::Display for LocateError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LocateError::NoAnchor => write!(f, "no file and no anchor to locate it with"),
+      LocateError::NotFound(anchor) => write!(f, "no script contains {anchor}"),
+      LocateError::Ambiguous(anchor, candidates) => write!(
+        f,
+        "{anchor} is found in several scripts: {}",
+        candidates
+          .iter()
+          .map(|suffix| suffix.display().to_string())
+          .collect::<Vec<_>>()
+          .join(", ")
+      )
+    }
+  }
+}
+
+/// Where the base content of a generated file comes from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FileOrigin {
+  /// The existing file in the output folder
+  Output,
+  MergedFiles,
+  Mod(String),
+  #[default]
+  Content0
+}
+
+impl FileOrigin {
+  fn from_mod_scripts(scripts: &Path) -> Self {
+    // the mod folders point to `<mod>/content/scripts`
+    let name = scripts
+      .ancestors()
+      .nth(2)
+      .and_then(|m| m.file_name())
+      .map(|n| n.to_string_lossy().into_owned())
+      .unwrap_or_default();
+
+    Self::Mod(name)
+  }
+}
+
+impl std::fmt::Display for FileOrigin {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FileOrigin::Output => write!(f, "output"),
+      FileOrigin::MergedFiles => write!(f, "MergedFiles"),
+      FileOrigin::Mod(name) => write!(f, "{name}"),
+      FileOrigin::Content0 => write!(f, "content0")
+    }
+  }
+}
+
+/// The folders the base files are read from, indexed so the files are found
+/// whatever the case of their path
+struct ScriptTrees {
+  output: ScriptIndex,
+  merged: ScriptIndex,
+  mods: Vec<ScriptIndex>,
+  content0: ScriptIndex
+}
+
+impl ScriptTrees {
+  /// The mods are kept in the order the game loads them, see [ModSettings]
+  fn new(game_root: &Path, out: &Path, mod_settings: &ModSettings) -> std::io::Result<Self> {
+    let mods = paths::mod_folders(game_root, out, mod_settings)?
+      .into_iter()
+      .map(ScriptIndex::new)
+      .collect();
+
+    Ok(Self {
+      output: ScriptIndex::new(out.to_path_buf()),
+      merged: ScriptIndex::new(paths::merge_scripts(game_root)),
+      mods,
+      content0: ScriptIndex::new(paths::content_scripts(game_root))
+    })
+  }
+
+  /// The path of the file with the casing the game uses, which is the one of
+  /// content0 then the one of the MergedFiles and the mods for the new files.
+  fn canonical(&self, file_suffix: &Path) -> PathBuf {
+    std::iter::once(&self.content0)
+      .chain(std::iter::once(&self.merged))
+      .chain(&self.mods)
+      .chain(std::iter::once(&self.output))
+      .find_map(|tree| tree.suffix(file_suffix))
+      .cloned()
+      .unwrap_or_else(|| file_suffix.to_path_buf())
+  }
+
+  /// List the script files the `file()` patterns can match, from content0 and
+  /// if allowed from the MergedFiles and the mods, with their canonical case.
+  /// Sorted so the patterns expand to the same files in the same order on
+  /// every build.
+  fn candidates(&self, search_behaviour: FileSearchBehaviour) -> Vec<PathBuf> {
+    let mut trees = vec![&self.content0];
+    if let FileSearchBehaviour::Content0AndMods = search_behaviour {
+      trees.push(&self.merged);
+      trees.extend(&self.mods);
+    }
+
+    let mut files = BTreeMap::new();
+    for tree in trees {
+      for suffix in tree.files() {
+        files
+          .entry(ScriptIndex::key(suffix))
+          .or_insert_with(|| suffix.clone());
+      }
+    }
+
+    files.into_values().collect()
+  }
+}
+
 #[derive(Clone, Copy)]
 pub enum FileSearchBehaviour {
   Content0,
   Content0AndMods
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::CError;
+
+  /// Write the files in a new game folder
+  fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("cahirp-pool-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    for (path, contents) in files {
+      let path = root.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, contents).unwrap();
+    }
+
+    std::fs::create_dir_all(root.join("mods")).unwrap();
+    root
+  }
+
+  fn directive(module: &str, recipe: &str) -> Directive {
+    let (_, mut directive) = Directive::parse(recipe).unwrap();
+    directive.origin.module = module.to_owned();
+    directive.id = DirectiveId::derive(&[module, &directive.content()]);
+
+    directive
+  }
+
+  fn scripts(files: &[(&str, &str)]) -> BTreeMap<PathBuf, FoundFile> {
+    files
+      .iter()
+      .map(|(suffix, contents)| {
+        let file = (
+          PathBuf::from(suffix),
+          FileOrigin::Content0,
+          contents.to_string(),
+          TextFormat::default()
+        );
+
+        (PathBuf::from(suffix), file)
+      })
+      .collect()
+  }
+
+  fn pool(game_root: &Path, directives: Vec<Directive>, strict: bool) -> CResult<FilePool> {
+    FilePool::new(
+      directives,
+      game_root,
+      &game_root.join("out"),
+      FileSearchBehaviour::Content0AndMods,
+      true,
+      &ModSettings::default(),
+      strict
+    )
+  }
+
+  #[test]
+  fn the_file_is_located_from_the_first_anchor() {
+    let scripts = scripts(&[
+      ("game/a.ws", "class A {\n\tfunction Shared() {}\n}\n"),
+      ("game/b.ws", "class B {\n\tfunction Shared() {}\n}\n")
+    ]);
+
+    let located = |recipe: &str| FilePool::locate_file(&directive("modA", recipe), &scripts);
+
+    assert_eq!(
+      located("@insert(\n  at(class B)\n  at(function Shared)\n)\nx();").unwrap(),
+      PathBuf::from("game/b.ws")
+    );
+    assert!(matches!(
+      located("@insert(\n  at(class C)\n)\nx();"),
+      Err(LocateError::NotFound(anchor)) if anchor == "class C"
+    ));
+    assert!(matches!(
+      located("@insert(\n  at(function Shared)\n)\nx();"),
+      Err(LocateError::Ambiguous(_, candidates)) if candidates.len() == 2
+    ));
+    assert!(matches!(
+      located("@insert(\n  note(nowhere)\n)\nx();"),
+      Err(LocateError::NoAnchor)
+    ));
+  }
+
+  #[test]
+  fn a_file_that_cant_be_located_fails_a_strict_build() {
+    let game_root = fixture(
+      "locate",
+      &[
+        (
+          "content/content0/scripts/game/a.ws",
+          "class A {\n\tfunction Shared() {}\n}\n"
+        ),
+        (
+          "content/content0/scripts/game/b.ws",
+          "class B {\n\tfunction Shared() {}\n}\n"
+        )
+      ]
+    );
+
+    let ambiguous = || {
+      vec![directive(
+        "modA",
+        "@insert(\n  at(function Shared)\n)\nx();"
+      )]
+    };
+    let lenient = pool(&game_root, ambiguous(), false);
+    let strict = pool(&game_root, ambiguous(), true);
+    std::fs::remove_dir_all(&game_root).unwrap();
+
+    let lenient = lenient.ok().unwrap();
+    let (suffix, miss) = &lenient.unresolved.values().next().unwrap()[0];
+    assert_eq!(suffix, Path::new(AUTO_FILE));
+    assert!(matches!(&miss.parameter, Parameter::At(anchor) if anchor == "function Shared"));
+
+    assert!(matches!(
+      strict,
+      Err(CError::Locate(LocateError::Ambiguous(..)))
+    ));
+  }
+
+  #[test]
+  fn files_keep_the_case_the_game_uses() {
+    let game_root = fixture(
+      "canonical",
+      &[
+        ("content/content0/scripts/game/Inventory.ws", ""),
+        ("mods/modA/content/scripts/game/player/R4Player.ws", ""),
+        ("mods/modA/content/scripts/game/inventory.ws", "")
+      ]
+    );
+
+    let trees = ScriptTrees::new(&game_root, &game_root.join("out"), &ModSettings::default());
+    std::fs::remove_dir_all(&game_root).unwrap();
+    let trees = trees.unwrap();
+
+    // a new file of a mod keeps the case of the mod, while content0 is the
+    // reference for the files it has
+    assert_eq!(
+      trees.canonical(Path::new("game/player/r4player.ws")),
+      PathBuf::from("game/player/R4Player.ws")
+    );
+    assert_eq!(
+      trees.canonical(Path::new("game/inventory.ws")),
+      PathBuf::from("game/Inventory.ws")
+    );
+    assert_eq!(
+      trees.canonical(Path::new("game/new.ws")),
+      PathBuf::from("game/new.ws")
+    );
+  }
+
+  #[test]
+  fn windows_1252_files_that_cant_hold_the_code_are_written_in_utf16() {
+    let game_root = fixture("unrepresentable", &[]);
+    let script = game_root.join("content/content0/scripts/game/player.ws");
+    std::fs::create_dir_all(script.parent().unwrap()).unwrap();
+    std::fs::write(&script, b"// caf\xe9\nclass A {\n}\n").unwrap();
+
+    let out = game_root.join("out");
+    std::fs::create_dir_all(&out).unwrap();
+
+    let directives = vec![directive(
+      "modA",
+      "@insert(\n  file(game/player.ws)\n  below(class A {)\n)\n// \u{2192}"
+    )];
+    let persisted = pool(&game_root, directives, true)
+      .and_then(|pool| Ok(pool.emit(&out, &[], MarkerStyle::None, None, false)?))
+      .and_then(|pool| Ok(pool.persist(&out)?));
+    let bytes = std::fs::read(out.join("game/player.ws"));
+    let written = read_script(&out.join("game/player.ws"));
+    std::fs::remove_dir_all(&game_root).unwrap();
+
+    persisted.unwrap();
+    let bytes = bytes.unwrap();
+    let (contents, format) = written.unwrap();
+
+    assert_eq!(&bytes[..2], [0xFF, 0xFE]);
+    assert_eq!(format.encoding, TextEncoding::Utf16le);
+    assert!(contents.contains("// caf\u{e9}") && contents.contains("// \u{2192}"));
+  }
+
+  /// Emit the directives in the files of the fixture and give the contents of
+  /// the files afterwards
+  fn emit_contents(name: &str, recipe: &str, atomic: bool) -> Vec<(PathBuf, String)> {
+    let game_root = fixture(
+      name,
+      &[
+        ("content/content0/scripts/game/a.ws", "class A {\n}\n"),
+        ("content/content0/scripts/game/b.ws", "class B {\n}\n")
+      ]
+    );
+    let out = game_root.join("out");
+
+    let files = pool(&game_root, vec![directive("modA", recipe)], false)
+      .and_then(|pool| Ok(pool.emit(&out, &[], MarkerStyle::None, None, atomic)?))
+      .map(|pool| pool.into_files(&out));
+    std::fs::remove_dir_all(&game_root).unwrap();
+
+    files
+      .unwrap()
+      .into_iter()
+      .map(|(suffix, file)| (suffix, file.contents))
+      .collect()
+  }
+
+  #[test]
+  fn a_miss_in_a_file_rolls_the_others_back() {
+    let recipe = "@insert(\n  file(game/a.ws)\n  file(game/b.ws)\n  below(class A {)\n)\nx();";
+
+    assert_eq!(
+      emit_contents("atomic-miss", recipe, true),
+      [
+        (PathBuf::from("game/a.ws"), "class A {\n}\n".to_owned()),
+        (PathBuf::from("game/b.ws"), "class B {\n}\n".to_owned())
+      ]
+    );
+    assert_eq!(
+      emit_contents("each-miss", recipe, false)[0],
+      (
+        PathBuf::from("game/a.ws"),
+        "class A {\nx();\n}\n".to_owned()
+      )
+    );
+  }
+
+  #[test]
+  fn a_named_file_that_doesnt_exist_aborts_the_directive() {
+    let recipe =
+      "@insert(\n  file(game/a.ws)\n  file(game/missing.ws)\n  below(class A {)\n)\nx();";
+
+    let contents = emit_contents("atomic-unresolved", recipe, true);
+    assert!(contents
+      .iter()
+      .all(|(_, contents)| !contents.contains("x();")));
+
+    let contents = emit_contents("each-unresolved", recipe, false);
+    assert!(contents.contains(&(
+      PathBuf::from("game/a.ws"),
+      "class A {\nx();\n}\n".to_owned()
+    )));
+  }
+}
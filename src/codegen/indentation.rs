@@ -0,0 +1,239 @@
+/// Width of a tab when the indentation of the emitted code mixes tabs and
+/// spaces.
+const TAB_WIDTH: usize = 4;
+
+/// Controls how the emitted code is indented, set with the `indent(mode)`
+/// parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum IndentMode {
+  /// Strip the common indentation of the code block, re-base it on the
+  /// indentation of the target line and use the indentation style of the file.
+  #[default]
+  Auto,
+
+  /// Emit the code exactly as it is written in the recipe.
+  Keep,
+
+  /// Remove any indentation from the emitted code.
+  None
+}
+
+impl IndentMode {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name.trim() {
+      "auto" => Some(Self::Auto),
+      "keep" => Some(Self::Keep),
+      "none" => Some(Self::None),
+      _ => None
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Auto => "auto",
+      Self::Keep => "keep",
+      Self::None => "none"
+    }
+  }
+}
+
+/// The string used for a single level of indentation in a block of code
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IndentUnit {
+  Tab,
+  Spaces(usize)
+}
+
+impl IndentUnit {
+  /// Deduce the indentation unit of a block of code by looking at how its
+  /// lines start, tabs win as soon as they are the majority.
+  fn detect<'a>(lines: impl Iterator<Item = &'a str>) -> Option<Self> {
+    let mut tabs = 0;
+    let mut spaces = 0;
+    let mut space_unit = 0;
+
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+      if line.starts_with('\t') {
+        tabs += 1;
+      } else if line.starts_with(' ') {
+        spaces += 1;
+
+        let width = line.len() - line.trim_start_matches(' ').len();
+        space_unit = gcd(space_unit, width);
+      }
+    }
+
+    match (tabs, spaces) {
+      (0, 0) => None,
+      (tabs, spaces) if tabs >= spaces => Some(Self::Tab),
+      _ => Some(Self::Spaces(space_unit.clamp(1, 8)))
+    }
+  }
+
+  fn width(&self) -> usize {
+    match self {
+      IndentUnit::Tab => TAB_WIDTH,
+      IndentUnit::Spaces(n) => *n
+    }
+  }
+
+  fn render(&self, levels: usize) -> String {
+    match self {
+      IndentUnit::Tab => "\t".repeat(levels),
+      IndentUnit::Spaces(n) => " ".repeat(levels * n)
+    }
+  }
+}
+
+/// Indent the lines of `code` for them to be emitted right after `surrounding`
+/// in `file`, every emitted line ends with a `\n`.
+pub fn indent_code(code: &str, surrounding: &str, file: &str, mode: IndentMode) -> String {
+  let mut output = String::with_capacity(code.len());
+
+  match mode {
+    IndentMode::Keep => {
+      for line in code.lines() {
+        output.push_str(line.trim_end());
+        output.push('\n');
+      }
+    }
+    IndentMode::None => {
+      for line in code.lines() {
+        output.push_str(line.trim());
+        output.push('\n');
+      }
+    }
+    IndentMode::Auto => {
+      let target = previous_line_indentation(surrounding);
+      let file_unit = IndentUnit::detect(file.lines())
+        .or_else(|| IndentUnit::detect(std::iter::once(target)))
+        .unwrap_or(IndentUnit::Tab);
+
+      let code_unit = IndentUnit::detect(code.lines()).unwrap_or(file_unit);
+      let base_width = code
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| indentation_width(l, code_unit))
+        .min()
+        .unwrap_or(0);
+
+      for line in code.lines() {
+        let trimmed = line.trim();
+
+        if !trimmed.is_empty() {
+          // anything that doesn't fit a full level is alignment, which is kept
+          // as spaces
+          let relative = indentation_width(line, code_unit) - base_width;
+          let levels = relative / code_unit.width();
+          let alignment = relative % code_unit.width();
+
+          output.push_str(target);
+          output.push_str(&file_unit.render(levels));
+          output.push_str(&" ".repeat(alignment));
+          output.push_str(trimmed);
+        }
+
+        output.push('\n');
+      }
+    }
+  }
+
+  output
+}
+
+/// Get the indentation of the last complete line of `surrounding`
+fn previous_line_indentation(surrounding: &str) -> &str {
+  let end = surrounding.rfind('\n').unwrap_or(0);
+  let start = surrounding[..end].rfind('\n').map(|i| i + 1).unwrap_or(0);
+  let line = &surrounding[start..end];
+
+  &line[..line.len() - line.trim_start().len()]
+}
+
+/// Get the width of the indentation of the line, where tabs count as a full
+/// level of the given unit.
+fn indentation_width(line: &str, unit: IndentUnit) -> usize {
+  line
+    .chars()
+    .take_while(|c| c.is_whitespace())
+    .map(|c| match c {
+      '\t' => unit.width(),
+      _ => 1
+    })
+    .sum()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+  match b {
+    0 => a,
+    b => gcd(b, a % b)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FILE: &str = "class A {\n\tfunction f() {\n\t\tvar x: int;\n\t}\n}\n";
+
+  #[test]
+  fn auto_rebases_the_code_on_the_target_line() {
+    let surrounding = "class A {\n\tfunction f() {\n\t\tvar x: int;\n";
+    let code = "    if (x) {\n      y();\n    }";
+
+    assert_eq!(
+      indent_code(code, surrounding, FILE, IndentMode::Auto),
+      "\t\tif (x) {\n\t\t\ty();\n\t\t}\n"
+    );
+  }
+
+  #[test]
+  fn auto_keeps_the_alignment_as_spaces() {
+    let surrounding = "class A {\n\tfunction f() {\n";
+    let code = "call(a,\n\t  b);";
+
+    assert_eq!(
+      indent_code(code, surrounding, FILE, IndentMode::Auto),
+      "\tcall(a,\n\t\t  b);\n"
+    );
+  }
+
+  #[test]
+  fn auto_uses_the_indentation_of_the_file() {
+    let file = "class A {\n  function f() {\n    var x: int;\n  }\n}\n";
+    let surrounding = "class A {\n  function f() {\n";
+    let code = "if (x) {\n\ty();\n}";
+
+    assert_eq!(
+      indent_code(code, surrounding, file, IndentMode::Auto),
+      "  if (x) {\n    y();\n  }\n"
+    );
+  }
+
+  #[test]
+  fn keep_and_none() {
+    let code = "  a();\n\tb();  ";
+
+    assert_eq!(
+      indent_code(code, "\t\tx\n", FILE, IndentMode::Keep),
+      "  a();\n\tb();\n"
+    );
+    assert_eq!(
+      indent_code(code, "\t\tx\n", FILE, IndentMode::None),
+      "a();\nb();\n"
+    );
+  }
+
+  #[test]
+  fn detect_the_indentation_unit() {
+    assert_eq!(
+      IndentUnit::detect(["\ta", "\tb", "  c"].into_iter()),
+      Some(IndentUnit::Tab)
+    );
+    assert_eq!(
+      IndentUnit::detect(["  a", "    b"].into_iter()),
+      Some(IndentUnit::Spaces(2))
+    );
+    assert_eq!(IndentUnit::detect(["a", ""].into_iter()), None);
+  }
+}
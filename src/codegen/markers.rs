@@ -0,0 +1,191 @@
+use crate::parser::{Directive, DirectiveId};
+
+use super::conflicts::normalize_code;
+
+/// Prefix of the generated markers, so they can be told apart from the
+/// comments written by the recipe authors.
+const MARKER_PREFIX: &str = "// cahirp:";
+
+/// Controls the comments that are generated around every block of emitted
+/// code, so the merged output tells which mod injected which lines.
+///
+/// The code emitted inside a line with `after` or `before` is never marked, as
+/// a line comment would swallow the rest of the line. The source map still
+/// records it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum MarkerStyle {
+  /// No marker is generated
+  #[default]
+  None,
+
+  /// `BEGIN` and `END` markers with the name of the mod
+  Short,
+
+  /// Like [MarkerStyle::Short] but with the recipe file, the directive id and
+  /// the notes of the directive
+  Full
+}
+
+/// The lines of comments that wrap a block of emitted code
+pub struct Markers {
+  pub begin: String,
+  pub end: String
+}
+
+impl MarkerStyle {
+  pub fn markers(&self, directive: &Directive) -> Option<Markers> {
+    let module = &directive.origin.module;
+
+    match self {
+      MarkerStyle::None => None,
+      MarkerStyle::Short => Some(Markers {
+        begin: format!("{MARKER_PREFIX} {module} - BEGIN"),
+        end: format!("{MARKER_PREFIX} {module} - END")
+      }),
+      MarkerStyle::Full => {
+        let origin = &directive.origin;
+        let mut begin = format!(
+          "{MARKER_PREFIX} {module} - BEGIN (recipe={}:{}-{}, directive={})",
+          origin.recipe_name(),
+          origin.line,
+          origin.end_line,
+          directive.id
+        );
+
+        for note in directive.parameters().notes() {
+          begin.push('\n');
+          begin.push_str(&format!("{MARKER_PREFIX} {}", note.replace('\n', " ")));
+        }
+
+        Some(Markers {
+          begin,
+          end: format!("{MARKER_PREFIX} {module} - END")
+        })
+      }
+    }
+  }
+}
+
+/// Whether the file has the code wrapped in the markers of the mod, which means
+/// an earlier build emitted it whatever the marker style of this one. The
+/// `full` markers also name the directive, so the same code emitted by two
+/// directives of the mod isn't mistaken for one another.
+pub fn is_marked(file: &str, module: &str, id: DirectiveId, code: &str) -> bool {
+  let begin = format!("{MARKER_PREFIX} {module} - BEGIN");
+  let end = format!("{MARKER_PREFIX} {module} - END");
+  let directive = format!("directive={id})");
+  let code = normalize_code(code);
+
+  let mut lines = file.lines().map(str::trim);
+  while let Some(line) = lines.next() {
+    let details = match line.strip_prefix(&begin) {
+      Some(details) => details.trim(),
+      None => continue
+    };
+
+    if !details.is_empty() && !details.ends_with(&directive) {
+      continue;
+    }
+
+    // the notes of the full markers are between the markers as well
+    let block: Vec<&str> = lines
+      .by_ref()
+      .take_while(|line| *line != end)
+      .filter(|line| !line.starts_with(MARKER_PREFIX))
+      .collect();
+
+    if normalize_code(&block.join("\n")) == code {
+      return true;
+    }
+  }
+
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Origin;
+
+  fn directive(input: &str) -> Directive {
+    let (_, mut directive) = Directive::parse(input).unwrap();
+    directive.origin = Origin {
+      module: "modA".to_owned(),
+      recipe: "cahirp/a.ws".into(),
+      line: 1,
+      end_line: 3
+    };
+    directive.id = DirectiveId::derive(&["modA", "a.ws", "first"]);
+
+    directive
+  }
+
+  fn wrap(markers: &Markers, code: &str) -> String {
+    format!(
+      "class A {{\n{}\n{code}\n{}\n}}\n",
+      markers.begin, markers.end
+    )
+  }
+
+  #[test]
+  fn marker_styles() {
+    let directive = directive("@insert(\n  note(\"some\nnote\")\n  at(class A)\n)\nfoo();");
+    let id = directive.id;
+
+    assert!(MarkerStyle::None.markers(&directive).is_none());
+
+    let short = MarkerStyle::Short.markers(&directive).unwrap();
+    assert_eq!(short.begin, "// cahirp: modA - BEGIN");
+    assert_eq!(short.end, "// cahirp: modA - END");
+
+    let full = MarkerStyle::Full.markers(&directive).unwrap();
+    assert_eq!(
+      full.begin,
+      format!("// cahirp: modA - BEGIN (recipe=a.ws:1-3, directive={id})\n// cahirp: some note")
+    );
+    assert_eq!(full.end, short.end);
+  }
+
+  #[test]
+  fn marked_code_is_found_whatever_the_whitespace() {
+    let directive = directive("@insert(\n  at(class A)\n)\nfoo();");
+    let file = wrap(
+      &MarkerStyle::Short.markers(&directive).unwrap(),
+      "\t\tfoo( );"
+    );
+
+    assert!(is_marked(&file, "modA", directive.id, "foo( );"));
+    assert!(is_marked(
+      &file,
+      "modA",
+      DirectiveId::default(),
+      "  foo( );\n"
+    ));
+    assert!(!is_marked(&file, "modB", directive.id, "foo( );"));
+    assert!(!is_marked(&file, "modA", directive.id, "bar();"));
+  }
+
+  #[test]
+  fn full_markers_are_tied_to_their_directive() {
+    let directive = directive("@insert(\n  note(hello)\n  at(class A)\n)\nfoo();");
+    let file = wrap(&MarkerStyle::Full.markers(&directive).unwrap(), "foo();");
+
+    assert!(is_marked(&file, "modA", directive.id, "foo();"));
+    assert!(!is_marked(
+      &file,
+      "modA",
+      DirectiveId::derive(&["modA", "a.ws", "second"]),
+      "foo();"
+    ));
+  }
+
+  #[test]
+  fn unmarked_code_is_not_marked() {
+    assert!(!is_marked(
+      "class A {\nfoo();\n}\n",
+      "modA",
+      DirectiveId::default(),
+      "foo();"
+    ));
+  }
+}
@@ -1,14 +1,32 @@
 mod emitter;
-pub use emitter::CodeEmitter;
+pub use emitter::{CodeEmitter, Emission, EmitOptions};
+
+mod indentation;
+pub use indentation::IndentMode;
+
+mod markers;
+pub use markers::{MarkerStyle, Markers};
+
+mod source_map;
+pub use source_map::{Edit, SourceFile, SourceMap, SourceMapEntry, SOURCE_MAP_FILE};
+
+mod conflicts;
+pub use conflicts::{Conflict, ConflictGroups, ConflictKind};
+
+mod once;
+pub use once::OnceMode;
 
 mod cursor;
-pub use cursor::CodeCursor;
+pub use cursor::{CodeCursor, CursorStep, Miss};
 
 mod file_pool;
-pub use file_pool::{FilePool, FileSearchBehaviour};
+pub use file_pool::{FileOrigin, FilePool, FileSearchBehaviour, LocateError};
 
 mod orchestrator;
-pub use orchestrator::ExecutionOrchestrator;
+pub use orchestrator::{ExecutionOrchestrator, Stage};
+
+mod unmet;
+pub use unmet::{unmet_conditions, UnmetCondition, INSTALLED_PREFIX};
 
 mod export_database;
-pub use export_database::ExportDatabase;
+pub use export_database::{ExportDatabase, ExportKey, ExportLookupError, UseError, MAX_USE_DEPTH};
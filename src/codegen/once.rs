@@ -0,0 +1,203 @@
+use super::conflicts::normalize_code;
+
+/// Where to look for the code of a directive before emitting it, to skip the
+/// emission when it is already there. For example when a mod's changes were
+/// already merged in the MergedFiles with the Script Merger.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OnceMode {
+  /// Around the position the cursor reached
+  Location,
+
+  /// In the block of code, delimited by braces, the cursor is in
+  Scope
+}
+
+impl OnceMode {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name.trim() {
+      "location" => Some(Self::Location),
+      "scope" => Some(Self::Scope),
+      _ => None
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Location => "location",
+      Self::Scope => "scope"
+    }
+  }
+
+  /// Whether the code is already present in the file for a cursor at `idx`
+  /// with a selection of `selection_len` bytes. The comparison ignores
+  /// whitespace so a difference of indentation isn't enough to emit the code
+  /// twice.
+  pub fn is_present(&self, file: &str, idx: usize, selection_len: usize, code: &str) -> bool {
+    match self {
+      // code that was already inserted at the location is right after the
+      // cursor, or right before it when the cursor moved past it
+      OnceMode::Location => {
+        let idx = idx.min(file.len());
+
+        // the selection is replaced by the code, so it's only there if the
+        // selection is the code itself
+        match selection_len {
+          0 => starts_with_code(&file[idx..], code) || ends_with_code(&file[..idx], code),
+          _ => starts_with_code(&file[idx..], code)
+        }
+      }
+      OnceMode::Scope => {
+        let (start, end) = enclosing_scope(file, idx);
+
+        contains_code(safe_slice(file, start, end), code)
+      }
+    }
+  }
+}
+
+/// Whether the code appears anywhere in the file, whitespace aside
+pub fn contains_code(file: &str, code: &str) -> bool {
+  let code = normalize_code(code);
+
+  !code.is_empty() && normalize_code(file).contains(&code)
+}
+
+/// Whether the text starts with the code, whitespace aside
+fn starts_with_code(text: &str, code: &str) -> bool {
+  let code: Vec<char> = code.chars().filter(|c| !c.is_whitespace()).collect();
+
+  !code.is_empty()
+    && text
+      .chars()
+      .filter(|c| !c.is_whitespace())
+      .take(code.len())
+      .eq(code.iter().copied())
+}
+
+/// Whether the text ends with the code, whitespace aside
+fn ends_with_code(text: &str, code: &str) -> bool {
+  let code: Vec<char> = code.chars().rev().filter(|c| !c.is_whitespace()).collect();
+
+  !code.is_empty()
+    && text
+      .chars()
+      .rev()
+      .filter(|c| !c.is_whitespace())
+      .take(code.len())
+      .eq(code.iter().copied())
+}
+
+/// Find the bounds of the block delimited by braces around `idx`, or the whole
+/// file if there is none.
+fn enclosing_scope(file: &str, idx: usize) -> (usize, usize) {
+  let idx = idx.min(file.len());
+  let bytes = file.as_bytes();
+
+  let mut depth = 0;
+  let mut start = 0;
+  for i in (0..idx).rev() {
+    match bytes[i] {
+      b'}' => depth += 1,
+      b'{' if depth == 0 => {
+        start = i;
+        break;
+      }
+      b'{' => depth -= 1,
+      _ => {}
+    }
+  }
+
+  let mut depth = 0;
+  let mut end = file.len();
+  for (i, &byte) in bytes.iter().enumerate().skip(idx) {
+    match byte {
+      b'{' => depth += 1,
+      b'}' if depth == 0 => {
+        end = i + 1;
+        break;
+      }
+      b'}' => depth -= 1,
+      _ => {}
+    }
+  }
+
+  (start, end)
+}
+
+/// Slice the file while moving the bounds to the closest char boundaries
+fn safe_slice(file: &str, start: usize, end: usize) -> &str {
+  let mut start = start.min(file.len());
+  let mut end = end.min(file.len());
+
+  while !file.is_char_boundary(start) {
+    start -= 1;
+  }
+
+  while !file.is_char_boundary(end) {
+    end += 1;
+  }
+
+  &file[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FILE: &str = "class A {\n\tfunction f() {\n\t\tfoo();\n\t\tbar();\n\t}\n\n\tfunction g() {\n\t\tbaz();\n\t}\n}\n";
+
+  fn idx_of(pattern: &str) -> usize {
+    FILE.find(pattern).unwrap()
+  }
+
+  #[test]
+  fn location_looks_right_after_and_right_before_the_cursor() {
+    let location = OnceMode::Location;
+
+    assert!(location.is_present(FILE, idx_of("\t\tfoo"), 0, "foo();"));
+    assert!(location.is_present(FILE, idx_of("\t\tbar"), 0, "  foo();\n"));
+    assert!(location.is_present(FILE, idx_of("\t\tfoo"), 0, "foo();\nbar();"));
+  }
+
+  #[test]
+  fn location_ignores_the_code_elsewhere() {
+    let location = OnceMode::Location;
+
+    assert!(!location.is_present(FILE, idx_of("\t\tbar"), 0, "baz();"));
+    assert!(!location.is_present(FILE, idx_of("\t}\n\n"), 0, "foo();"));
+  }
+
+  #[test]
+  fn location_with_a_selection_only_looks_at_the_selection() {
+    let location = OnceMode::Location;
+
+    assert!(location.is_present(FILE, idx_of("bar"), "bar();".len(), "bar();"));
+    assert!(!location.is_present(FILE, idx_of("bar"), "bar();".len(), "foo();"));
+  }
+
+  #[test]
+  fn scope_looks_in_the_enclosing_block() {
+    let scope = OnceMode::Scope;
+
+    assert!(scope.is_present(FILE, idx_of("\t\tfoo"), 0, "bar();"));
+    assert!(scope.is_present(FILE, idx_of("\t\tbaz"), 0, "baz();"));
+    assert!(!scope.is_present(FILE, idx_of("\t\tbaz"), 0, "foo();"));
+    assert!(scope.is_present(FILE, idx_of("\tfunction g"), 0, "foo();"));
+  }
+
+  #[test]
+  fn contains_code_ignores_whitespace() {
+    assert!(contains_code(FILE, "foo();   bar();"));
+    assert!(!contains_code(FILE, "foo(); baz();"));
+    assert!(!contains_code(FILE, " \n"));
+  }
+
+  #[test]
+  fn names() {
+    for mode in [OnceMode::Location, OnceMode::Scope] {
+      assert_eq!(OnceMode::from_name(mode.name()), Some(mode));
+    }
+
+    assert_eq!(OnceMode::from_name("everywhere"), None);
+  }
+}
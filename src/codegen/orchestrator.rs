@@ -1,15 +1,63 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
 
 use crate::cli::prints::verbose_debug;
 use crate::parser::Directive;
 
+use super::INSTALLED_PREFIX;
+
+/// The phase a directive runs in, set with the `stage(n)` parameter. The stages
+/// run one after the other in ascending order, and the variables defined in a
+/// stage are visible to the stages that follow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stage(pub i32);
+
+impl Stage {
+  pub const EARLY: Self = Self(-1);
+  pub const DEFAULT: Self = Self(0);
+  pub const LATE: Self = Self(1);
+
+  /// Parse either a number or one of the named phases: `early`, `default` and
+  /// `late`.
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name.trim() {
+      "early" => Some(Self::EARLY),
+      "default" => Some(Self::DEFAULT),
+      "late" => Some(Self::LATE),
+      n => n.parse().ok().map(Self)
+    }
+  }
+}
+
+impl Display for Stage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match *self {
+      Self::EARLY => write!(f, "early"),
+      Self::DEFAULT => write!(f, "default"),
+      Self::LATE => write!(f, "late"),
+      Self(n) => write!(f, "{n}")
+    }
+  }
+}
+
 /// Orchestrates the execution of [Directive]s with the variables that are
 /// defined with [`Define`] and the requirements expressed by [`IfDef`] and
 /// [`IfNotDef`].
+///
+/// The directives are first split by [Stage], the iterations then go over the
+/// directives of a single stage until none of them can run, at which point the
+/// next stage starts.
 pub struct ExecutionOrchestrator<'a> {
   pub to_run: Vec<&'a Directive>,
   to_skip: Vec<&'a Directive>,
 
+  /// The stages that didn't start yet, the next one is last
+  pending_stages: Vec<(Stage, Vec<&'a Directive>)>,
+  stage: Stage,
+
+  /// The directives of the finished stages that never ran
+  never_ran: Vec<&'a Directive>,
+
   iteration: usize,
   pub finished: bool
 }
@@ -19,26 +67,138 @@ where
   'defs: 'directives
 {
   pub fn new(directives: &'directives Vec<Directive>, defs: &HashSet<&'defs str>) -> Self {
-    Self::next_iteration(0, directives.iter(), defs)
+    let mut stages: BTreeMap<Stage, Vec<&'directives Directive>> = BTreeMap::new();
+    for directive in directives {
+      stages
+        .entry(directive.parameters().stage())
+        .or_default()
+        .push(directive);
+    }
+
+    // the first iteration starts right away with the directives of the first
+    // stage
+    let mut pending_stages: Vec<_> = stages.into_iter().rev().collect();
+    let (stage, directives) = pending_stages.pop().unwrap_or((Stage::DEFAULT, Vec::new()));
+
+    let mut orchestrator = Self {
+      to_run: Vec::new(),
+      to_skip: Vec::new(),
+      pending_stages,
+      stage,
+      never_ran: Vec::new(),
+      iteration: 0,
+      finished: false
+    };
+
+    orchestrator.advance(0, directives, defs);
+    orchestrator
+  }
+
+  /// The variables the builds start with, the names of all the mods that are
+  /// installed with a special prefix to clearly indicate these are the
+  /// installed mods.
+  pub fn installed_variables(mod_names: &[String]) -> Vec<String> {
+    mod_names
+      .iter()
+      .map(|s| format!("{INSTALLED_PREFIX}{s}"))
+      .collect()
+  }
+
+  /// Run every iteration over the directives, `run` gets the directives of each
+  /// iteration along with its index and the variables they define are then
+  /// visible to the next iterations. Returns the directives that never ran and
+  /// the variables that were defined at the end.
+  pub fn run(
+    directives: &'directives Vec<Directive>, installed: &'defs [String],
+    mut run: impl FnMut(&[&'directives Directive], usize)
+  ) -> (Vec<&'directives Directive>, HashSet<&'directives str>) {
+    let mut variables: HashSet<&'directives str> = installed.iter().map(String::as_str).collect();
+    let mut orchestrator = ExecutionOrchestrator::new(directives, &variables);
+
+    while !orchestrator.finished {
+      run(&orchestrator.to_run, orchestrator.iteration);
+
+      for &dir in &orchestrator.to_run {
+        for define in dir.parameters().defines() {
+          if crate::VERBOSE {
+            verbose_debug(format!("define({define})"));
+          }
+
+          variables.insert(define);
+        }
+      }
+
+      orchestrator.next(&variables);
+    }
+
+    if crate::VERBOSE {
+      verbose_debug("directive processing queue empty".to_owned());
+    }
+
+    (orchestrator.never_ran, variables)
   }
 
   pub fn next(&mut self, defs: &HashSet<&'defs str>) {
     // start from the directives that were previously skipped
-    let new = Self::next_iteration(self.iteration + 1, self.to_skip.iter().map(|&d| d), defs);
+    let directives = std::mem::take(&mut self.to_skip);
+
+    self.advance(self.iteration + 1, directives, defs);
+  }
+
+  /// The index of the current iteration, starting at 0
+  pub fn iteration(&self) -> usize {
+    self.iteration
+  }
+
+  /// The stage of the directives of the current iteration
+  pub fn stage(&self) -> Stage {
+    self.stage
+  }
+
+  /// The directives that never ran, complete once the orchestrator is finished
+  pub fn skipped(&self) -> &[&'directives Directive] {
+    &self.never_ran
+  }
+
+  /// Run an iteration over the directives, then move on to the next stages as
+  /// long as there is no directive to run.
+  fn advance(
+    &mut self, iteration: usize, mut directives: Vec<&'directives Directive>,
+    defs: &HashSet<&'defs str>
+  ) {
+    loop {
+      let (to_run, to_skip) = Self::next_iteration(iteration, self.stage, directives, defs);
+
+      self.iteration = iteration;
+      self.to_run = to_run;
+      self.to_skip = to_skip;
 
-    // a bit weird that it mutates itself off a new `Self` rather than returning
-    // it here but the borrow rules don't allow it since the orchestrator will
-    // often be used while in for loops
-    self.iteration = new.iteration;
-    self.to_run = new.to_run;
-    self.to_skip = new.to_skip;
-    self.finished = new.finished;
+      if !self.to_run.is_empty() {
+        self.finished = false;
+        return;
+      }
+
+      // what is left in a finished stage never runs, even if a later stage
+      // defines the variables it waits for
+      self.never_ran.append(&mut self.to_skip);
+
+      match self.pending_stages.pop() {
+        Some((stage, next)) => {
+          self.stage = stage;
+          directives = next;
+        }
+        None => {
+          self.finished = true;
+          return;
+        }
+      }
+    }
   }
 
-  pub fn next_iteration<I>(iteration: usize, directives: I, defs: &HashSet<&'defs str>) -> Self
-  where
-    I: Iterator<Item = &'directives Directive>
-  {
+  fn next_iteration(
+    iteration: usize, stage: Stage, directives: Vec<&'directives Directive>,
+    defs: &HashSet<&'defs str>
+  ) -> (Vec<&'directives Directive>, Vec<&'directives Directive>) {
     let mut second_pass = Vec::new();
     let mut to_run = Vec::new();
     let mut to_skip = Vec::new();
@@ -82,9 +242,9 @@ where
     }
 
     if crate::VERBOSE {
-      verbose_debug(format!("iteration={iteration}"));
+      verbose_debug(format!("iteration={iteration} stage={stage}"));
       if !to_run.is_empty() {
-        verbose_debug(format!("directives to run:"));
+        verbose_debug("directives to run:".to_owned());
 
         for d in &to_run {
           verbose_debug(format!("- {}", d.id));
@@ -92,7 +252,7 @@ where
       }
 
       if !to_skip.is_empty() {
-        verbose_debug(format!("directives to skip:"));
+        verbose_debug("directives to skip:".to_owned());
 
         for d in &to_skip {
           verbose_debug(format!("- {}", d.id));
@@ -100,12 +260,7 @@ where
       }
     }
 
-    Self {
-      finished: to_run.is_empty(),
-      to_run,
-      to_skip,
-      iteration
-    }
+    (to_run, to_skip)
   }
 }
 
@@ -122,3 +277,142 @@ fn fits_requirements(directive: &Directive, defs: &HashSet<&str>) -> bool {
 
   all_ifdefs && all_ifndefs
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::DirectiveId;
+
+  /// A directive named after its `note` with the given parameters
+  fn directive(name: &str, params: &[&str]) -> Directive {
+    let params: String = params.iter().map(|p| format!("  {p}\n")).collect();
+    let (_, mut directive) = Directive::parse(&format!(
+      "@insert(\n  note({name})\n  file(game/player.ws)\n{params})\n{name}();"
+    ))
+    .unwrap();
+    directive.id = DirectiveId::derive(&[name]);
+
+    directive
+  }
+
+  fn name(directive: &Directive) -> &str {
+    directive.parameters().notes().next().unwrap()
+  }
+
+  /// Run the directives like a build would and list the directives of each
+  /// iteration, then the ones that never ran
+  fn iterations(directives: &Vec<Directive>) -> (Vec<(Stage, Vec<&str>)>, Vec<&str>) {
+    let mut variables = HashSet::new();
+    let mut orchestrator = ExecutionOrchestrator::new(directives, &variables);
+    let mut iterations = Vec::new();
+
+    while !orchestrator.finished {
+      let mut names: Vec<&str> = orchestrator.to_run.iter().map(|d| name(d)).collect();
+      names.sort();
+      iterations.push((orchestrator.stage(), names));
+
+      for directive in &orchestrator.to_run {
+        variables.extend(directive.parameters().defines());
+      }
+
+      orchestrator.next(&variables);
+    }
+
+    let mut skipped: Vec<&str> = orchestrator.skipped().iter().map(|d| name(d)).collect();
+    skipped.sort();
+
+    (iterations, skipped)
+  }
+
+  #[test]
+  fn stages_run_in_order() {
+    let directives = vec![
+      directive("late", &["stage(late)"]),
+      directive("default", &[]),
+      directive("numbered", &["stage(5)"]),
+      directive("early", &["stage(early)"]),
+    ];
+
+    let (iterations, skipped) = iterations(&directives);
+
+    assert_eq!(
+      iterations,
+      [
+        (Stage::EARLY, vec!["early"]),
+        (Stage::DEFAULT, vec!["default"]),
+        (Stage::LATE, vec!["late"]),
+        (Stage(5), vec!["numbered"])
+      ]
+    );
+    assert!(skipped.is_empty());
+  }
+
+  #[test]
+  fn defines_are_visible_to_the_later_stages() {
+    let directives = vec![
+      directive("user", &["stage(late)", "ifdef(x)"]),
+      directive("definer", &["stage(early)", "define(x)"]),
+      directive("same_stage", &["stage(early)", "ifdef(x)"]),
+    ];
+
+    let (iterations, skipped) = iterations(&directives);
+
+    assert_eq!(
+      iterations,
+      [
+        (Stage::EARLY, vec!["definer"]),
+        (Stage::EARLY, vec!["same_stage"]),
+        (Stage::LATE, vec!["user"])
+      ]
+    );
+    assert!(skipped.is_empty());
+  }
+
+  #[test]
+  fn ifndefs_wait_for_the_rest_of_their_stage() {
+    let directives = vec![
+      directive("fallback", &["ifndef(x)"]),
+      directive("first", &["define(y)"]),
+      directive("second", &["ifdef(y)", "define(x)"]),
+      directive("late_fallback", &["stage(late)", "ifndef(z)"]),
+    ];
+
+    let (iterations, skipped) = iterations(&directives);
+
+    assert_eq!(
+      iterations,
+      [
+        (Stage::DEFAULT, vec!["first"]),
+        (Stage::DEFAULT, vec!["second"]),
+        (Stage::LATE, vec!["late_fallback"])
+      ]
+    );
+    assert_eq!(skipped, ["fallback"]);
+  }
+
+  #[test]
+  fn what_is_left_in_a_stage_never_runs() {
+    let directives = vec![
+      directive("waiting", &["stage(early)", "ifdef(x)"]),
+      directive("definer", &["define(x)"]),
+      directive("never", &["ifdef(nothing)"]),
+    ];
+
+    let (iterations, skipped) = iterations(&directives);
+
+    assert_eq!(iterations, [(Stage::DEFAULT, vec!["definer"])]);
+    assert_eq!(skipped, ["never", "waiting"]);
+  }
+
+  #[test]
+  fn stage_names() {
+    assert_eq!(Stage::from_name("early"), Some(Stage::EARLY));
+    assert_eq!(Stage::from_name(" default "), Some(Stage::DEFAULT));
+    assert_eq!(Stage::from_name("late"), Some(Stage::LATE));
+    assert_eq!(Stage::from_name("-3"), Some(Stage(-3)));
+    assert_eq!(Stage::from_name("later"), None);
+
+    assert_eq!(Stage(-1).to_string(), "early");
+    assert_eq!(Stage(2).to_string(), "2");
+  }
+}
@@ -0,0 +1,452 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{encoded_offsets, TextFormat};
+use crate::parser::{Directive, DirectiveId};
+
+use super::conflicts::normalize_code;
+use super::{ConflictKind, FileOrigin};
+
+/// Name of the source map file written at the root of the output folder
+pub const SOURCE_MAP_FILE: &str = "cahirp.map.json";
+
+/// The content of a file being generated, along with the ranges of code each
+/// directive emitted in it.
+#[derive(Debug, Default, Clone)]
+pub struct SourceFile {
+  pub contents: String,
+  pub spans: Vec<GeneratedSpan>,
+
+  /// The content of the file before any directive ran, and where it comes from
+  pub base: String,
+  pub origin: FileOrigin,
+
+  /// The format of the file it is based on, which it's written back in
+  pub format: TextFormat
+}
+
+/// A range of bytes in a generated file that was emitted by a directive
+#[derive(Debug, Clone)]
+pub struct GeneratedSpan {
+  pub start: usize,
+  pub end: usize,
+  pub directive: DirectiveId,
+
+  /// Whether the code replaced a selection rather than being inserted
+  pub selection: bool,
+
+  /// The code of the directive, normalized to compare it with other blocks
+  pub code: String
+}
+
+/// Describes how a directive changed a file: `removed` bytes starting at
+/// `start` were replaced by `inserted` bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct Edit {
+  pub start: usize,
+  pub removed: usize,
+  pub inserted: usize,
+
+  /// Whether the removed bytes were selected by the directive, rather than
+  /// being whitespace trimmed around the insertion
+  pub selection: bool
+}
+
+impl SourceFile {
+  pub fn new(contents: String, origin: FileOrigin, format: TextFormat) -> Self {
+    Self {
+      base: contents.clone(),
+      contents,
+      spans: Vec::new(),
+      origin,
+      format
+    }
+  }
+
+  /// Replace the contents of the file with the result of an [Edit] made by the
+  /// directive, the previous spans are moved or cut accordingly.
+  ///
+  /// Returns the conflicts between the edit and the code the other directives
+  /// emitted before it.
+  pub fn apply(
+    &mut self, contents: String, edit: Edit, directive: DirectiveId, code: &str
+  ) -> Vec<(ConflictKind, DirectiveId)> {
+    let edit_end = edit.start + edit.removed;
+    let inserted_end = edit.start + edit.inserted;
+    let code = normalize_code(code);
+
+    let mut conflicts = Vec::new();
+    for span in self.spans.iter().filter(|span| span.directive != directive) {
+      let overlaps = edit.start < span.end && span.start < edit_end;
+
+      if edit.selection && overlaps {
+        conflicts.push((
+          match span.selection {
+            true => ConflictKind::OverlappingSelection,
+            false => ConflictKind::CutsGeneratedCode
+          },
+          span.directive
+        ));
+      }
+
+      // an insertion right at the edge of a span is next to it rather than in it
+      if !edit.selection && span.start < edit.start && edit.start < span.end {
+        conflicts.push((ConflictKind::InsertsIntoGeneratedCode, span.directive));
+      }
+
+      let adjacent = span.end == edit.start || span.start == edit_end;
+      if !edit.selection && adjacent && span.code == code {
+        conflicts.push((ConflictKind::DuplicateInsertion, span.directive));
+      }
+    }
+
+    for span in &mut self.spans {
+      // the parts of the span that are before and after the removed bytes are
+      // kept, the part that was removed is lost
+      let start = match span.start {
+        s if s < edit.start => s,
+        s if s < edit_end => inserted_end,
+        s => s - edit_end + inserted_end
+      };
+
+      let end = match span.end {
+        e if e <= edit.start => e,
+        e if e <= edit_end => edit.start,
+        e => e - edit_end + inserted_end
+      };
+
+      span.start = start;
+      span.end = end;
+    }
+
+    self.spans.retain(|span| span.start < span.end);
+
+    if edit.inserted > 0 {
+      self.spans.push(GeneratedSpan {
+        start: edit.start,
+        end: inserted_end,
+        directive,
+        selection: edit.selection,
+        code
+      });
+    }
+
+    self.contents = contents;
+
+    conflicts
+  }
+}
+
+/// Maps the ranges of generated code in the output files back to the recipes
+/// and directives that emitted them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SourceMap {
+  /// The entries of each file, keyed by their path relative to the output
+  /// folder and sorted by their position in the file.
+  pub files: BTreeMap<PathBuf, Vec<SourceMapEntry>>,
+
+  /// Where the base content of each file comes from, absent from the source
+  /// maps of older builds
+  #[serde(default)]
+  pub origins: BTreeMap<PathBuf, String>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceMapEntry {
+  /// Byte range in the generated file as it is written on disk, in its
+  /// encoding and with its line endings
+  pub start: usize,
+  pub end: usize,
+
+  /// Line range in the generated file, 1-based & inclusive
+  pub start_line: usize,
+  pub end_line: usize,
+
+  pub module: String,
+  pub recipe: PathBuf,
+  pub directive: String,
+
+  /// Line of the directive in its recipe, 1-based
+  pub recipe_line: usize,
+
+  /// Last line of the directive in its recipe, absent from the source maps of
+  /// older builds
+  #[serde(default)]
+  pub recipe_end_line: usize
+}
+
+impl SourceMap {
+  pub fn add_file(
+    &mut self, file_suffix: PathBuf, file: &SourceFile,
+    directives: &HashMap<DirectiveId, &Directive>
+  ) {
+    let mut spans = file.spans.clone();
+    spans.sort_by_key(|span| span.start);
+
+    // the spans are offsets in the decoded contents, while the entries give the
+    // offsets in the file as it is written on disk
+    let mut offsets: Vec<usize> = spans
+      .iter()
+      .flat_map(|span| [span.start, span.end])
+      .collect();
+    offsets.sort();
+    offsets.dedup();
+
+    let on_disk: HashMap<usize, usize> = offsets
+      .iter()
+      .copied()
+      .zip(encoded_offsets(&file.contents, file.format, &offsets))
+      .collect();
+
+    let entries = spans
+      .into_iter()
+      .filter_map(|span| {
+        let directive = directives.get(&span.directive)?;
+
+        Some(SourceMapEntry {
+          start: on_disk[&span.start],
+          end: on_disk[&span.end],
+          start_line: line_at(&file.contents, span.start),
+          // the span ends on a \n in most cases, which belongs to the line
+          // before it
+          end_line: line_at(&file.contents, span.end.saturating_sub(1).max(span.start)),
+          module: directive.origin.module.clone(),
+          recipe: directive.origin.recipe.clone(),
+          directive: directive.id.to_string(),
+          recipe_line: directive.origin.line,
+          recipe_end_line: directive.origin.end_line
+        })
+      })
+      .collect();
+
+    self
+      .origins
+      .insert(file_suffix.clone(), file.origin.to_string());
+    self.files.insert(file_suffix, entries);
+  }
+
+  pub fn read(out: &Path) -> std::io::Result<Self> {
+    let contents = std::fs::read_to_string(out.join(SOURCE_MAP_FILE))?;
+
+    serde_json::from_str(&contents).map_err(std::io::Error::other)
+  }
+
+  pub fn write(&self, out: &Path) -> std::io::Result<()> {
+    let contents = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+
+    std::fs::write(out.join(SOURCE_MAP_FILE), contents)
+  }
+
+  /// Get the entries of the file, paths are compared the way the game does it:
+  /// ignoring the case and the kind of separators.
+  pub fn file_entries(&self, file_suffix: &Path) -> Option<&Vec<SourceMapEntry>> {
+    find_file(&self.files, file_suffix)
+  }
+
+  /// Get where the base content of the file comes from, see [`FileOrigin`]
+  pub fn file_origin(&self, file_suffix: &Path) -> Option<&str> {
+    find_file(&self.origins, file_suffix).map(String::as_str)
+  }
+
+  /// Translate a line of the generated file that no directive emitted into its
+  /// line in the base file, by removing the generated lines above it.
+  pub fn base_line(&self, file_suffix: &Path, line: usize) -> usize {
+    let Some(entries) = self.file_entries(file_suffix) else {
+      return line;
+    };
+
+    // entries emitted inside other entries are sorted after them, so only the
+    // lines past the end of the previous range are counted
+    let mut generated = 0;
+    let mut counted_until = 0;
+    for entry in entries.iter().filter(|entry| entry.end_line < line) {
+      let start = entry.start_line.max(counted_until + 1);
+
+      if start <= entry.end_line {
+        generated += entry.end_line - start + 1;
+        counted_until = entry.end_line;
+      }
+    }
+
+    line.saturating_sub(generated)
+  }
+
+  /// Find the entry that generated the given line of the file, if any. When
+  /// code was emitted inside the code of another directive the innermost entry
+  /// is returned.
+  pub fn lookup(&self, file_suffix: &Path, line: usize) -> Option<&SourceMapEntry> {
+    self
+      .file_entries(file_suffix)?
+      .iter()
+      .filter(|entry| entry.start_line <= line && line <= entry.end_line)
+      .min_by_key(|entry| entry.end - entry.start)
+  }
+}
+
+/// Get the value of the file, paths are compared the way the game does it:
+/// ignoring the case and the kind of separators.
+fn find_file<'a, T>(files: &'a BTreeMap<PathBuf, T>, file_suffix: &Path) -> Option<&'a T> {
+  let normalize = |p: &Path| p.to_string_lossy().replace('\\', "/").to_lowercase();
+  let file_suffix = normalize(file_suffix);
+
+  files
+    .iter()
+    .find(|(path, _)| normalize(path) == file_suffix)
+    .map(|(_, value)| value)
+}
+
+/// Get the 1-based line number of the byte at `idx`
+fn line_at(contents: &str, idx: usize) -> usize {
+  let idx = idx.min(contents.len());
+
+  contents.as_bytes()[..idx]
+    .iter()
+    .filter(|&&b| b == b'\n')
+    .count()
+    + 1
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ids() -> (DirectiveId, DirectiveId) {
+    (DirectiveId::derive(&["a"]), DirectiveId::derive(&["b"]))
+  }
+
+  fn file(contents: &str) -> SourceFile {
+    SourceFile::new(
+      contents.to_owned(),
+      FileOrigin::default(),
+      TextFormat::default()
+    )
+  }
+
+  /// Replace `removed` bytes at `start` with the code, like the emitter does
+  fn edit(
+    file: &mut SourceFile, directive: DirectiveId, start: usize, removed: usize, code: &str
+  ) -> Vec<(ConflictKind, DirectiveId)> {
+    let contents = format!(
+      "{}{code}{}",
+      &file.contents[..start],
+      &file.contents[start + removed..]
+    );
+    let edit = Edit {
+      start,
+      removed,
+      inserted: code.len(),
+      selection: removed > 0
+    };
+
+    file.apply(contents, edit, directive, code)
+  }
+
+  fn spans(file: &SourceFile) -> Vec<(DirectiveId, &str)> {
+    file
+      .spans
+      .iter()
+      .map(|span| (span.directive, &file.contents[span.start..span.end]))
+      .collect()
+  }
+
+  #[test]
+  fn spans_follow_the_edits() {
+    let (a, b) = ids();
+    let mut file = file("a;\nb;\nc;\n");
+
+    assert!(edit(&mut file, a, 3, 0, "x;\n").is_empty());
+    assert!(edit(&mut file, b, 0, 2, "y;").is_empty());
+
+    assert_eq!(file.contents, "y;\nx;\nb;\nc;\n");
+    assert_eq!(spans(&file), [(a, "x;\n"), (b, "y;")]);
+  }
+
+  #[test]
+  fn selections_over_generated_code() {
+    let (a, b) = ids();
+    let mut file = file("a;\nb;\n");
+    edit(&mut file, a, 0, 2, "x = 1;");
+
+    assert_eq!(
+      edit(&mut file, b, 0, 6, "x = 2;"),
+      [(ConflictKind::OverlappingSelection, a)]
+    );
+
+    let mut file = self::file("a;\nb;\n");
+    edit(&mut file, a, 3, 0, "x();\ny();\n");
+
+    assert_eq!(
+      edit(&mut file, b, 8, 4, "z();"),
+      [(ConflictKind::CutsGeneratedCode, a)]
+    );
+  }
+
+  #[test]
+  fn insertions_into_generated_code() {
+    let (a, b) = ids();
+    let mut file = file("a;\nb;\n");
+    edit(&mut file, a, 3, 0, "x();\ny();\n");
+
+    assert_eq!(
+      edit(&mut file, b, 8, 0, "z();\n"),
+      [(ConflictKind::InsertsIntoGeneratedCode, a)]
+    );
+
+    // right before or right after the generated code isn't in it
+    assert!(edit(&mut file, b, 3, 0, "w();\n").is_empty());
+    let after = file.contents.len() - "b;\n".len();
+    assert!(edit(&mut file, b, after, 0, "v();\n").is_empty());
+  }
+
+  #[test]
+  fn duplicate_insertions() {
+    let (a, b) = ids();
+    let mut file = file("a;\nb;\n");
+    edit(&mut file, a, 3, 0, "x();\n");
+
+    assert_eq!(
+      edit(&mut file, b, 8, 0, "  x();\n"),
+      [(ConflictKind::DuplicateInsertion, a)]
+    );
+    assert!(edit(&mut file, b, 0, 0, "x();\n").is_empty());
+  }
+
+  #[test]
+  fn a_directive_doesnt_conflict_with_itself() {
+    let (a, _) = ids();
+    let mut file = file("a;\nb;\n");
+    edit(&mut file, a, 3, 0, "x();\ny();\n");
+
+    assert!(edit(&mut file, a, 8, 0, "z();\n").is_empty());
+    assert!(edit(&mut file, a, 3, 5, "w();").is_empty());
+  }
+
+  #[test]
+  fn lines_that_werent_generated_are_translated_to_the_base_file() {
+    let (_, mut directive) = Directive::parse("@insert(\n  at(b;)\n)\nx();").unwrap();
+    directive.id = DirectiveId::derive(&["a"]);
+    let directives = HashMap::from([(directive.id, &directive)]);
+
+    let mut file = SourceFile::new(
+      "a;\nb;\nc;\nd;\n".to_owned(),
+      FileOrigin::MergedFiles,
+      TextFormat::default()
+    );
+    edit(&mut file, directive.id, 3, 0, "x();\ny();\n");
+    edit(&mut file, directive.id, 0, 0, "z();\n");
+    assert_eq!(file.contents, "z();\na;\nx();\ny();\nb;\nc;\nd;\n");
+
+    let mut source_map = SourceMap::default();
+    source_map.add_file(PathBuf::from("game/player.ws"), &file, &directives);
+
+    let suffix = Path::new("GAME\\Player.ws");
+    assert_eq!(source_map.file_origin(suffix), Some("MergedFiles"));
+    assert!(source_map.lookup(suffix, 4).is_some());
+    assert!(source_map.lookup(suffix, 6).is_none());
+    assert_eq!(source_map.base_line(suffix, 2), 1);
+    assert_eq!(source_map.base_line(suffix, 6), 3);
+    assert_eq!(source_map.base_line(Path::new("game/other.ws"), 6), 6);
+  }
+}
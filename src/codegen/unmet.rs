@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+
+use crate::parser::Directive;
+
+use super::Stage;
+
+/// The prefix of the variables defined for each installed mod
+pub const INSTALLED_PREFIX: &str = "installed.";
+
+/// A requirement of a directive that was still unmet when the build ended,
+/// which is why the directive never ran.
+#[derive(Debug, Clone)]
+pub enum UnmetCondition {
+  /// An `ifdef` whose variable was never defined, with the defined variables
+  /// that have a similar name
+  Undefined {
+    variable: String,
+    similar: Vec<String>
+  },
+
+  /// An `ifdef("installed.<mod>")` for a mod that isn't installed, with the
+  /// installed mods that have a similar name
+  NotInstalled {
+    module: String,
+    similar: Vec<String>
+  },
+
+  /// An `ifndef` whose variable was defined
+  Defined { variable: String },
+
+  /// The requirements were only met by a later stage, once the stage of the
+  /// directive was over
+  StageOver { stage: Stage }
+}
+
+/// List the conditions of the directive that aren't met by the variables that
+/// were defined at the end of the build.
+pub fn unmet_conditions(directive: &Directive, variables: &HashSet<&str>) -> Vec<UnmetCondition> {
+  let mut unmet = Vec::new();
+  let params = directive.parameters();
+
+  for variable in params.ifdefs().filter(|var| !variables.contains(var)) {
+    let condition = match variable.strip_prefix(INSTALLED_PREFIX) {
+      Some(module) => UnmetCondition::NotInstalled {
+        module: module.to_owned(),
+        similar: similar_names(
+          module,
+          variables
+            .iter()
+            .filter_map(|var| var.strip_prefix(INSTALLED_PREFIX))
+        )
+      },
+      None => UnmetCondition::Undefined {
+        variable: variable.to_owned(),
+        similar: similar_names(
+          variable,
+          variables
+            .iter()
+            .copied()
+            .filter(|var| !var.starts_with(INSTALLED_PREFIX))
+        )
+      }
+    };
+
+    unmet.push(condition);
+  }
+
+  for variable in params.ifndefs().filter(|var| variables.contains(var)) {
+    unmet.push(UnmetCondition::Defined {
+      variable: variable.to_owned()
+    });
+  }
+
+  if unmet.is_empty() {
+    unmet.push(UnmetCondition::StageOver {
+      stage: params.stage()
+    });
+  }
+
+  unmet
+}
+
+/// Find the names that are likely a typo of `name`, sorted from the closest
+fn similar_names<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<String> {
+  let name = name.to_lowercase();
+  let length = name.chars().count();
+  let max_distance = (length / 4).max(1);
+
+  let mut similar: Vec<(usize, &str)> = candidates
+    .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), candidate))
+    // a name can't be similar if all of its letters have to change
+    .filter(|(distance, _)| *distance <= max_distance && *distance < length)
+    .collect();
+
+  similar.sort();
+  similar.dedup();
+  similar
+    .into_iter()
+    .map(|(_, candidate)| candidate.to_owned())
+    .collect()
+}
+
+/// The Levenshtein distance between the two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1; b.len() + 1];
+
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + (ca != *cb) as usize;
+
+      current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+    }
+
+    previous = current;
+  }
+
+  previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn directive(params: &str) -> Directive {
+    let (_, directive) = Directive::parse(&format!(
+      "@insert(\n  file(game/player.ws)\n{params})\nx();"
+    ))
+    .unwrap();
+
+    directive
+  }
+
+  #[test]
+  fn unmet_ifdefs_and_ifndefs() {
+    let variables = HashSet::from([
+      "repair_kits",
+      "installed.modFriendlyHud",
+      "installed.modA",
+      "done"
+    ]);
+    let directive = directive(
+      "  ifdef(repairkits)\n  ifdef(installed.modFriendlyHUD2)\n  ifndef(done)\n  ifndef(other)\n"
+    );
+
+    let unmet = unmet_conditions(&directive, &variables);
+
+    assert_eq!(unmet.len(), 3);
+    assert!(matches!(
+      &unmet[0],
+      UnmetCondition::Undefined { variable, similar } if variable == "repairkits" && similar == &["repair_kits"]
+    ));
+    // the installed mods are only compared with the other mods, without their
+    // prefix
+    assert!(matches!(
+      &unmet[1],
+      UnmetCondition::NotInstalled { module, similar } if module == "modFriendlyHUD2" && similar == &["modFriendlyHud"]
+    ));
+    assert!(matches!(&unmet[2], UnmetCondition::Defined { variable } if variable == "done"));
+  }
+
+  #[test]
+  fn met_conditions_were_met_too_late() {
+    let variables = HashSet::from(["x"]);
+    let unmet = unmet_conditions(&directive("  stage(early)\n  ifdef(x)\n"), &variables);
+
+    assert!(matches!(
+      unmet.as_slice(),
+      [UnmetCondition::StageOver {
+        stage: Stage::EARLY
+      }]
+    ));
+  }
+
+  #[test]
+  fn similar_names_are_sorted_from_the_closest() {
+    let candidates = [
+      "repair_kit",
+      "Repair_Kits",
+      "repairs",
+      "armor",
+      "repair_kits2"
+    ];
+
+    assert_eq!(
+      similar_names("repair_kits", candidates.into_iter()),
+      ["Repair_Kits", "repair_kit", "repair_kits2"]
+    );
+    // short names only allow a single change, and never a complete one
+    assert_eq!(
+      similar_names("ab", ["ac", "cd", "a"].into_iter()),
+      ["a", "ac"]
+    );
+    assert!(similar_names("a", ["b"].into_iter()).is_empty());
+  }
+
+  #[test]
+  fn edit_distances() {
+    assert_eq!(edit_distance("", ""), 0);
+    assert_eq!(edit_distance("abc", ""), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("flaw", "lawn"), 2);
+    assert_eq!(edit_distance("é", "e"), 1);
+  }
+}
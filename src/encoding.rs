@@ -1,27 +1,420 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::OnceLock;
 
-use encoding_rs_io::DecodeReaderBytesBuilder;
+/// The encoding of the files without a BOM, when set by the user rather than
+/// detected
+static ASSUMED_ENCODING: OnceLock<TextEncoding> = OnceLock::new();
 
-pub fn read_file(path: &PathBuf) -> std::io::Result<String> {
-  let contents = read_utf16_file(path)?;
-  // let contents = std::fs::read_to_string(path).or_else(|_| read_utf16_file(path))?;
+/// Decode every script without a BOM with the given encoding rather than guess
+/// it, the other files are still detected
+pub fn assume_encoding(encoding: TextEncoding) {
+  let _ = ASSUMED_ENCODING.set(encoding);
+}
+
+/// How the text of a file is stored on disk, so a generated file can be written
+/// back in the same format as the file it is based on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextFormat {
+  pub encoding: TextEncoding,
+
+  /// Whether the file starts with a byte order mark
+  pub bom: bool,
+  pub line_ending: LineEnding
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextEncoding {
+  #[default]
+  Utf8,
+
+  /// The encoding of most of the vanilla scripts
+  Utf16le,
+  Utf16be,
+
+  /// The encoding of the files written by older tools on western systems
+  Windows1252
+}
+
+impl Display for TextEncoding {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TextEncoding::Utf8 => write!(f, "UTF-8"),
+      TextEncoding::Utf16le => write!(f, "UTF-16LE"),
+      TextEncoding::Utf16be => write!(f, "UTF-16BE"),
+      TextEncoding::Windows1252 => write!(f, "Windows-1252")
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEnding {
+  #[default]
+  Lf,
+  Crlf
+}
+
+impl TextFormat {
+  /// The same format but with the given encoding. UTF-16 is always written with
+  /// a BOM as it's the only way for the game and the tools to recognize it,
+  /// while Windows-1252 can't have one.
+  pub fn with_encoding(self, encoding: TextEncoding) -> Self {
+    let bom = match encoding {
+      TextEncoding::Utf8 => self.bom,
+      TextEncoding::Utf16le | TextEncoding::Utf16be => true,
+      TextEncoding::Windows1252 => false
+    };
+
+    Self {
+      encoding,
+      bom,
+      ..self
+    }
+  }
+
+  /// Whether the encoding of a script was guessed, which is the case of every
+  /// script that isn't UTF-8 and has no BOM unless the encoding was given by
+  /// the user.
+  pub fn is_guessed(&self) -> bool {
+    self.is_detected() && ASSUMED_ENCODING.get().is_none()
+  }
+
+  /// Whether the encoding was detected from the bytes of the file alone
+  fn is_detected(&self) -> bool {
+    !self.bom && self.encoding != TextEncoding::Utf8
+  }
+}
+
+/// Read a file that isn't a script, such as a recipe, and decode it. Its
+/// encoding is always detected as `--assume-encoding` is about the scripts, a
+/// warning is printed if it had to be guessed.
+pub fn read_file(path: &Path) -> std::io::Result<String> {
+  let (contents, format) = read_with_format(path, None)?;
+
+  if format.is_detected() {
+    crate::cli::prints::encoding_guessed(&path.display().to_string(), format.encoding, false);
+  }
+
+  Ok(contents)
+}
+
+/// Read a script of the game or of a mod and decode it, with the encoding given
+/// by the user if it has no BOM. Every `\r` is removed from the contents so
+/// the directives only deal with `\n`, the [TextFormat] tells how to write it
+/// back.
+pub fn read_script(path: &Path) -> std::io::Result<(String, TextFormat)> {
+  read_with_format(path, ASSUMED_ENCODING.get().copied())
+}
+
+fn read_with_format(
+  path: &Path, assumed: Option<TextEncoding>
+) -> std::io::Result<(String, TextFormat)> {
+  let bytes = std::fs::read(path)?;
+  let (contents, mut format) = decode(&bytes, assumed);
+
+  // the line ending used the most is the one of the file, as files edited by
+  // different tools sometimes mix them
+  let crlf = contents.matches("\r\n").count();
+  let lf = contents.matches('\n').count() - crlf;
+  if crlf > lf {
+    format.line_ending = LineEnding::Crlf;
+  }
+
+  Ok((contents.replace("\r", ""), format))
+}
+
+fn decode(bytes: &[u8], assumed: Option<TextEncoding>) -> (String, TextFormat) {
+  let (encoding, bom_len) = match bytes {
+    [0xEF, 0xBB, 0xBF, ..] => (TextEncoding::Utf8, 3),
+    [0xFF, 0xFE, ..] => (TextEncoding::Utf16le, 2),
+    [0xFE, 0xFF, ..] => (TextEncoding::Utf16be, 2),
+    _ => {
+      let encoding = assumed.or_else(|| guess_utf16(bytes)).unwrap_or_else(|| {
+        match std::str::from_utf8(bytes) {
+          Ok(_) => TextEncoding::Utf8,
+          Err(_) => TextEncoding::Windows1252
+        }
+      });
+
+      (encoding, 0)
+    }
+  };
+
+  let decoder = match encoding {
+    TextEncoding::Utf8 => encoding_rs::UTF_8,
+    TextEncoding::Utf16le => encoding_rs::UTF_16LE,
+    TextEncoding::Utf16be => encoding_rs::UTF_16BE,
+    TextEncoding::Windows1252 => encoding_rs::WINDOWS_1252
+  };
+
+  let (contents, _) = decoder.decode_without_bom_handling(&bytes[bom_len..]);
+  let format = TextFormat {
+    encoding,
+    bom: bom_len > 0,
+    line_ending: LineEnding::Lf
+  };
+
+  (contents.into_owned(), format)
+}
+
+/// Guess whether text without a BOM is UTF-16 from its null bytes. The scripts
+/// are mostly ASCII, so in UTF-16 one byte out of two is null while null bytes
+/// don't appear in UTF-8 or Windows-1252 text.
+fn guess_utf16(bytes: &[u8]) -> Option<TextEncoding> {
+  let sample = &bytes[..bytes.len().min(4096)];
+  let pairs = sample.len() / 2;
+
+  let (even, odd) = sample.chunks_exact(2).fold((0, 0), |(even, odd), pair| {
+    (
+      even + (pair[0] == 0) as usize,
+      odd + (pair[1] == 0) as usize
+    )
+  });
 
-  Ok(contents.replace("\r", ""))
+  // a third of the pairs is a lot less than what ASCII heavy code gives, which
+  // leaves room for comments and strings in other alphabets
+  match (even * 3 > pairs, odd * 3 > pairs) {
+    (false, true) => Some(TextEncoding::Utf16le),
+    (true, false) => Some(TextEncoding::Utf16be),
+    _ => None
+  }
 }
 
-fn read_utf16_file(path: &PathBuf) -> std::io::Result<String> {
-  use std::io::Read;
+/// Encode the contents, which only use `\n`, in the given format
+pub fn encode(contents: &str, format: TextFormat) -> Vec<u8> {
+  let contents = match format.line_ending {
+    LineEnding::Lf => std::borrow::Cow::Borrowed(contents),
+    LineEnding::Crlf => std::borrow::Cow::Owned(contents.replace('\n', "\r\n"))
+  };
+
+  let mut bytes = Vec::with_capacity(contents.len() * 2 + 3);
+  match format.encoding {
+    TextEncoding::Utf8 => {
+      if format.bom {
+        bytes.extend_from_slice(&[0xEF, 0xBB, 0xBF]);
+      }
+
+      bytes.extend_from_slice(contents.as_bytes());
+    }
+    TextEncoding::Utf16le => {
+      if format.bom {
+        bytes.extend_from_slice(&[0xFF, 0xFE]);
+      }
+
+      bytes.extend(contents.encode_utf16().flat_map(u16::to_le_bytes));
+    }
+    TextEncoding::Utf16be => {
+      if format.bom {
+        bytes.extend_from_slice(&[0xFE, 0xFF]);
+      }
+
+      bytes.extend(contents.encode_utf16().flat_map(u16::to_be_bytes));
+    }
+    TextEncoding::Windows1252 => {
+      // the characters Windows-1252 lacks are written as numeric character
+      // references by the encoder, the files that have some are written in
+      // another encoding, see [unrepresentable]
+      let (encoded, _, _) = encoding_rs::WINDOWS_1252.encode(&contents);
+
+      bytes.extend_from_slice(&encoded);
+    }
+  }
+
+  bytes
+}
+
+/// The characters of the contents that the encoding can't represent, along
+/// with their 1-based line. [encode] would write them as numeric character
+/// references such as `&#8364;`, which the game reads as is, so the files that
+/// have some are written in UTF-16 instead.
+pub fn unrepresentable(contents: &str, encoding: TextEncoding) -> Vec<(usize, char)> {
+  if encoding != TextEncoding::Windows1252 {
+    return Vec::new();
+  }
+
+  let is_representable = |c: char| {
+    let mut buffer = [0; 4];
+    let (_, _, had_errors) = encoding_rs::WINDOWS_1252.encode(c.encode_utf8(&mut buffer));
+
+    c.is_ascii() || !had_errors
+  };
+
+  contents
+    .lines()
+    .enumerate()
+    .flat_map(|(idx, line)| {
+      line
+        .chars()
+        .filter(move |&c| !is_representable(c))
+        .map(move |c| (idx + 1, c))
+    })
+    .collect()
+}
+
+/// Convert offsets in the contents, which only use `\n`, to offsets in the
+/// bytes [encode] gives for them in the given format. The offsets must be
+/// sorted.
+pub fn encoded_offsets(contents: &str, format: TextFormat, offsets: &[usize]) -> Vec<usize> {
+  let body = TextFormat {
+    bom: false,
+    ..format
+  };
+
+  // every format encodes a text piece by piece, so only the text between two
+  // offsets is encoded
+  let mut encoded = encode("", format).len();
+  let mut previous = 0;
+
+  offsets
+    .iter()
+    .map(|&offset| {
+      encoded += encode(&contents[previous..offset], body).len();
+      previous = offset;
+
+      encoded
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TEXT: &str = "class A {\n\t// café €\n}\n";
+
+  fn format(encoding: TextEncoding, bom: bool, line_ending: LineEnding) -> TextFormat {
+    TextFormat {
+      encoding,
+      bom,
+      line_ending
+    }
+  }
+
+  #[test]
+  fn the_bom_gives_the_encoding() {
+    for encoding in [
+      TextEncoding::Utf8,
+      TextEncoding::Utf16le,
+      TextEncoding::Utf16be
+    ] {
+      let format = format(encoding, true, LineEnding::Lf);
+      let (contents, decoded) = decode(&encode(TEXT, format), Some(TextEncoding::Windows1252));
+
+      assert_eq!(contents, TEXT);
+      assert_eq!(decoded, format);
+      assert!(!decoded.is_guessed());
+    }
+  }
+
+  #[test]
+  fn the_encoding_is_guessed_without_a_bom() {
+    for encoding in [
+      TextEncoding::Utf8,
+      TextEncoding::Utf16le,
+      TextEncoding::Utf16be,
+      TextEncoding::Windows1252
+    ] {
+      let format = format(encoding, false, LineEnding::Lf);
+      let (contents, decoded) = decode(&encode(TEXT, format), None);
+
+      assert_eq!(contents, TEXT);
+      assert_eq!(decoded, format);
+    }
+  }
+
+  #[test]
+  fn the_assumed_encoding_wins_over_the_guess() {
+    let bytes = encode(TEXT, format(TextEncoding::Utf8, false, LineEnding::Lf));
+    let (contents, decoded) = decode(&bytes, Some(TextEncoding::Windows1252));
+
+    assert_eq!(decoded.encoding, TextEncoding::Windows1252);
+    assert_eq!(contents, "class A {\n\t// cafÃ© â‚¬\n}\n");
+  }
+
+  #[test]
+  fn guess_utf16_from_null_bytes() {
+    assert_eq!(
+      guess_utf16(&[b'a', 0, b'b', 0]),
+      Some(TextEncoding::Utf16le)
+    );
+    assert_eq!(
+      guess_utf16(&[0, b'a', 0, b'b']),
+      Some(TextEncoding::Utf16be)
+    );
+    assert_eq!(guess_utf16(b"ab"), None);
+    assert_eq!(guess_utf16(&[]), None);
+  }
+
+  #[test]
+  fn the_line_ending_used_the_most_is_kept() {
+    let path = std::env::temp_dir().join(format!("cahirp-line-endings-{}.ws", std::process::id()));
+    std::fs::write(&path, "a\r\nb\r\nc\n").unwrap();
+    let read = read_with_format(&path, None);
+    std::fs::remove_file(&path).unwrap();
+
+    let (contents, format) = read.unwrap();
+    assert_eq!(contents, "a\nb\nc\n");
+    assert_eq!(format.line_ending, LineEnding::Crlf);
+    assert_eq!(encode(&contents, format), b"a\r\nb\r\nc\r\n");
+  }
+
+  #[test]
+  fn windows_1252_has_no_bom_and_utf16_always_has_one() {
+    let utf8 = format(TextEncoding::Utf8, true, LineEnding::Crlf);
+
+    assert!(!utf8.with_encoding(TextEncoding::Windows1252).bom);
+    assert!(
+      format(TextEncoding::Utf8, false, LineEnding::Lf)
+        .with_encoding(TextEncoding::Utf16le)
+        .bom
+    );
+    assert_eq!(utf8.with_encoding(TextEncoding::Utf8), utf8);
+  }
+
+  #[test]
+  fn unrepresentable_characters_and_their_line() {
+    let contents = "a\n// café € → ł\n→\n";
+
+    assert_eq!(
+      unrepresentable(contents, TextEncoding::Windows1252),
+      [(2, '→'), (2, 'ł'), (3, '→')]
+    );
+    assert!(unrepresentable(contents, TextEncoding::Utf16le).is_empty());
+    assert_eq!(
+      encode(
+        "→",
+        format(TextEncoding::Windows1252, false, LineEnding::Lf)
+      ),
+      b"&#8594;"
+    );
+  }
+
+  #[test]
+  fn offsets_in_the_encoded_bytes() {
+    let offsets: Vec<usize> = ["class", "// café", "}"]
+      .iter()
+      .map(|p| TEXT.find(p).unwrap())
+      .collect();
 
-  let file = File::open(path)?;
-  let source = BufReader::new(file);
+    for format in [
+      format(TextEncoding::Utf8, true, LineEnding::Lf),
+      format(TextEncoding::Utf16le, true, LineEnding::Crlf),
+      format(TextEncoding::Utf16be, false, LineEnding::Crlf),
+      format(TextEncoding::Windows1252, false, LineEnding::Lf)
+    ] {
+      let bytes = encode(TEXT, format);
 
-  let mut decoder = DecodeReaderBytesBuilder::new().build(source);
-  let mut dest = String::new();
-  // decoder implements the io::Read trait, so it can easily be plugged
-  // into any consumer expecting an arbitrary reader.
-  decoder.read_to_string(&mut dest)?;
+      for (offset, encoded) in offsets.iter().zip(encoded_offsets(TEXT, format, &offsets)) {
+        let expected = encode(
+          &TEXT[*offset..],
+          TextFormat {
+            bom: false,
+            ..format
+          }
+        );
 
-  Ok(dest)
+        assert_eq!(&bytes[encoded..], expected, "{format:?} at {offset}");
+      }
+    }
+  }
 }
@@ -1,25 +1,50 @@
 use std::error::Error;
 use std::fmt::Display;
 
+use crate::codegen::{LocateError, UseError};
+
 pub type CResult<T> = Result<T, CError>;
 
 #[derive(Debug)]
 pub enum CError {
   Io(std::io::Error),
-  WatchError(notify_debouncer_full::notify::Error)
+  WatchError(notify_debouncer_full::notify::Error),
+  Use(UseError),
+
+  /// The file of a directive couldn't be located from its first anchor
+  Locate(LocateError),
+
+  /// The output folder differs from what the build generates, holds the amount
+  /// of files that differ
+  OutdatedOutput(usize)
 }
 
 impl Display for CError {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
       CError::Io(e) => write!(f, "Io({e}"),
-      CError::WatchError(e) => write!(f, "WatchError({e}")
+      CError::WatchError(e) => write!(f, "WatchError({e}"),
+      CError::Use(e) => write!(f, "Use({e})"),
+      CError::Locate(e) => write!(f, "Locate({e})"),
+      CError::OutdatedOutput(n) => write!(f, "OutdatedOutput({n} files differ)")
     }
   }
 }
 
 impl Error for CError {}
 
+impl From<UseError> for CError {
+  fn from(value: UseError) -> Self {
+    Self::Use(value)
+  }
+}
+
+impl From<LocateError> for CError {
+  fn from(value: LocateError) -> Self {
+    Self::Locate(value)
+  }
+}
+
 impl From<std::io::Error> for CError {
   fn from(value: std::io::Error) -> Self {
     Self::Io(value)
@@ -0,0 +1,115 @@
+//! Matching of the `file()` patterns against the paths of the script files,
+//! with the usual wildcards:
+//! - `*` matches any part of a file or folder name
+//! - `?` matches a single character of a name
+//! - `**` as a whole segment matches any number of folders, including none
+
+/// Whether the `file()` parameter is a pattern rather than the path to a file
+pub fn is_pattern(file: &str) -> bool {
+  file.contains(['*', '?'])
+}
+
+/// Whether the path, relative to a scripts folder, matches the pattern. Both
+/// `/` and `\` are accepted as separators, and case is ignored like the game
+/// does.
+pub fn matches(pattern: &str, path: &str) -> bool {
+  let (pattern, path) = (pattern.to_lowercase(), path.to_lowercase());
+  let pattern: Vec<&str> = segments(&pattern).collect();
+  let path: Vec<&str> = segments(&path).collect();
+
+  match_segments(&pattern, &path)
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+  path
+    .split(['/', '\\'])
+    .filter(|segment| !segment.is_empty())
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+  match pattern.split_first() {
+    None => path.is_empty(),
+    Some((&"**", rest)) => (0..=path.len()).any(|skipped| match_segments(rest, &path[skipped..])),
+    Some((segment, rest)) => match path.split_first() {
+      Some((name, path)) => match_name(segment, name) && match_segments(rest, path),
+      None => false
+    }
+  }
+}
+
+/// Match a single file or folder name against a segment of the pattern
+fn match_name(segment: &str, name: &str) -> bool {
+  let segment: Vec<char> = segment.chars().collect();
+  let name: Vec<char> = name.chars().collect();
+
+  // the position in both the segment and the name right after the last `*`,
+  // to come back to when the characters that follow it stop matching
+  let mut star: Option<(usize, usize)> = None;
+  let (mut s, mut n) = (0, 0);
+
+  while n < name.len() {
+    match segment.get(s) {
+      Some('*') => {
+        star = Some((s + 1, n));
+        s += 1;
+      }
+      Some(&c) if c == '?' || c == name[n] => {
+        s += 1;
+        n += 1;
+      }
+      _ => match star {
+        // let the `*` match one more character
+        Some((after_star, matched)) => {
+          star = Some((after_star, matched + 1));
+          s = after_star;
+          n = matched + 1;
+        }
+        None => return false
+      }
+    }
+  }
+
+  segment[s..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn patterns() {
+    assert!(is_pattern("game/player/*.ws"));
+    assert!(is_pattern("game/r4?layer.ws"));
+    assert!(!is_pattern("game/player/r4Player.ws"));
+  }
+
+  #[test]
+  fn star_and_question_mark() {
+    assert!(matches("game/player/*.ws", "game/player/r4Player.ws"));
+    assert!(matches("game/player/r4*er.ws", "game/player/r4Player.ws"));
+    assert!(matches(
+      "game/player/r4?layer.ws",
+      "game/player/r4Player.ws"
+    ));
+    assert!(matches("game/*/r4Player.ws", "game/player/r4Player.ws"));
+    assert!(!matches("game/*.ws", "game/player/r4Player.ws"));
+    assert!(!matches("game/player/r4?.ws", "game/player/r4Player.ws"));
+  }
+
+  #[test]
+  fn double_star_matches_any_number_of_folders() {
+    assert!(matches("**/r4Player.ws", "r4Player.ws"));
+    assert!(matches("**/r4Player.ws", "game/player/r4Player.ws"));
+    assert!(matches("game/**/*.ws", "game/player/states/combat.ws"));
+    assert!(!matches("game/**/*.ws", "engine/player.ws"));
+  }
+
+  #[test]
+  fn separators_and_case_are_ignored() {
+    assert!(matches("game\\player\\*.ws", "game/player/r4Player.ws"));
+    assert!(matches(
+      "GAME/player/R4PLAYER.ws",
+      "game\\player\\r4Player.ws"
+    ));
+  }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::paths;
+
+/// The files of a scripts folder indexed by their path in lowercase, since the
+/// game resolves the script paths without regard to case while the filesystem
+/// may not.
+pub struct ScriptIndex {
+  root: PathBuf,
+
+  /// The lowercase paths mapped to the paths as they're written on disk, both
+  /// relative to the root
+  files: HashMap<String, PathBuf>
+}
+
+impl ScriptIndex {
+  /// Index the files of the scripts folder, a folder that doesn't exist has no
+  /// files.
+  pub fn new(root: PathBuf) -> Self {
+    let files = paths::script_files(&root)
+      .into_iter()
+      .map(|suffix| (Self::key(&suffix), suffix))
+      .collect();
+
+    Self { root, files }
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.root
+  }
+
+  /// The path of the file relative to the root as it's written on disk
+  pub fn suffix(&self, file_suffix: &Path) -> Option<&PathBuf> {
+    self.files.get(&Self::key(file_suffix))
+  }
+
+  /// The full path to the file, whatever the case of the given suffix
+  pub fn path(&self, file_suffix: &Path) -> Option<PathBuf> {
+    self
+      .suffix(file_suffix)
+      .map(|suffix| self.root.join(suffix))
+  }
+
+  /// The paths of the files relative to the root, as they're written on disk
+  pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+    self.files.values()
+  }
+
+  /// The key of a path in the index, which is the same for all the casings and
+  /// separators of a path
+  pub fn key(file_suffix: &Path) -> String {
+    file_suffix
+      .to_string_lossy()
+      .replace('\\', "/")
+      .to_lowercase()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn files_are_found_whatever_their_case() {
+    let root = std::env::temp_dir().join(format!("cahirp-index-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("game/Player")).unwrap();
+    std::fs::write(root.join("game/Player/R4Player.ws"), "").unwrap();
+
+    let index = ScriptIndex::new(root.clone());
+    let missing = ScriptIndex::new(root.join("missing"));
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let on_disk = PathBuf::from("game/Player/R4Player.ws");
+    assert_eq!(
+      index.suffix(Path::new("game/player/r4player.ws")),
+      Some(&on_disk)
+    );
+    assert_eq!(
+      index.suffix(Path::new("GAME\\PLAYER\\R4PLAYER.WS")),
+      Some(&on_disk)
+    );
+    assert_eq!(
+      index.path(Path::new("game/player/r4player.ws")),
+      Some(root.join(&on_disk))
+    );
+    assert_eq!(index.suffix(Path::new("game/player/r4player.w")), None);
+    assert_eq!(index.files().collect::<Vec<_>>(), [&on_disk]);
+
+    assert_eq!(missing.files().count(), 0);
+  }
+}
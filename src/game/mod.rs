@@ -1 +1,4 @@
+pub mod glob;
+pub mod index;
 pub mod paths;
+pub mod settings;
@@ -1,49 +1,162 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use super::settings::ModSettings;
 
 pub fn to_scripts(module: PathBuf) -> PathBuf {
-  module.join("content").join("scripts")
+  on_disk(&module, Path::new("content/scripts"))
 }
 
-pub fn cahirp_mod(game_root: &PathBuf) -> PathBuf {
+pub fn cahirp_mod(game_root: &Path) -> PathBuf {
   mods_folder(game_root).join("mod00000_Cahirp")
 }
 
-pub fn cahirp_scripts(game_root: &PathBuf) -> PathBuf {
+pub fn cahirp_scripts(game_root: &Path) -> PathBuf {
   to_scripts(cahirp_mod(game_root))
 }
 
-pub fn merge_scripts(game_root: &PathBuf) -> PathBuf {
-  to_scripts(mods_folder(game_root).join("mod0000_MergedFiles"))
+pub fn merge_scripts(game_root: &Path) -> PathBuf {
+  to_scripts(on_disk(
+    &mods_folder(game_root),
+    Path::new("mod0000_MergedFiles")
+  ))
 }
 
-pub fn content_scripts(game_root: &PathBuf) -> PathBuf {
-  game_root.join("content").join("content0").join("scripts")
+pub fn content_scripts(game_root: &Path) -> PathBuf {
+  on_disk(game_root, Path::new("content/content0/scripts"))
 }
 
-pub fn mods_folder(game_root: &PathBuf) -> PathBuf {
-  game_root.join("mods")
+pub fn mods_folder(game_root: &Path) -> PathBuf {
+  on_disk(game_root, Path::new("mods"))
 }
 
-/// Get the list of mod folders that aren't MergedFiles nor Cahirp files
-pub fn mod_folders(game_root: &PathBuf, out: &PathBuf) -> std::io::Result<Vec<PathBuf>> {
+/// Get the list of mod folders that aren't MergedFiles nor Cahirp files, in the
+/// order the game loads them so the first mod that has a file is the one the
+/// game would use.
+pub fn mod_folders(
+  game_root: &Path, out: &Path, settings: &ModSettings
+) -> std::io::Result<Vec<PathBuf>> {
   let merge_folder = merge_scripts(game_root);
   let cahirp_folder = cahirp_scripts(game_root);
 
-  let is_enabled = |m: &PathBuf| {
-    m.file_name()
-      .and_then(|m| m.to_str())
-      .map(|n| !n.starts_with("~"))
-      .unwrap_or(false)
-  };
-
-  let folders = std::fs::read_dir(mods_folder(game_root))?
-    .into_iter()
+  let mut mods = std::fs::read_dir(mods_folder(game_root))?
     .filter_map(|e| e.ok())
     .map(|m| m.path())
-    .filter(is_enabled)
+    .filter(|m| settings.is_enabled(&folder_name(m)))
+    .collect::<Vec<PathBuf>>();
+
+  settings.sort_by_priority(&mut mods, folder_name);
+
+  let folders = mods
+    .into_iter()
     .map(to_scripts)
-    .filter(|m| m != &cahirp_folder && m != &merge_folder && m != out)
+    .filter(|m| !same_path(m, &cahirp_folder) && !same_path(m, &merge_folder) && !same_path(m, out))
     .collect::<Vec<PathBuf>>();
 
   Ok(folders)
 }
+
+/// The folder the game reads `mods.settings` from, in the documents of the
+/// user
+pub fn documents_folder() -> PathBuf {
+  let home = std::env::var_os("USERPROFILE")
+    .or_else(|| std::env::var_os("HOME"))
+    .map(PathBuf::from)
+    .unwrap_or_default();
+
+  home.join("Documents").join("The Witcher 3")
+}
+
+pub fn folder_name(folder: &Path) -> String {
+  folder
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_default()
+}
+
+/// Find the path to `relative` inside `base` whatever the case of its folders
+/// and file, as the game doesn't care about it while the filesystem may.
+pub fn find_case_insensitive(base: &Path, relative: &Path) -> Option<PathBuf> {
+  let exact = base.join(relative);
+  if exact.exists() {
+    return Some(exact);
+  }
+
+  let mut path = base.to_path_buf();
+  for component in relative.components() {
+    let name = component.as_os_str().to_string_lossy().to_lowercase();
+
+    path = std::fs::read_dir(&path)
+      .ok()?
+      .filter_map(|e| e.ok())
+      .find(|e| e.file_name().to_string_lossy().to_lowercase() == name)?
+      .path();
+  }
+
+  Some(path)
+}
+
+/// The path to `relative` inside `base` with the case it has on disk, or as it
+/// is given if it doesn't exist.
+fn on_disk(base: &Path, relative: &Path) -> PathBuf {
+  find_case_insensitive(base, relative).unwrap_or_else(|| base.join(relative))
+}
+
+/// Whether both paths lead to the same place for the game, which ignores case
+fn same_path(a: &Path, b: &Path) -> bool {
+  a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+}
+
+/// List the files inside the scripts folder and its subfolders, as paths
+/// relative to the scripts folder. A folder that doesn't exist has no files.
+pub fn script_files(scripts: &Path) -> Vec<PathBuf> {
+  fn walk(scripts: &Path, folder: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(folder) else {
+      return;
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+      let path = entry.path();
+
+      if path.is_dir() {
+        walk(scripts, &path, files);
+      } else if let Ok(suffix) = path.strip_prefix(scripts) {
+        files.push(suffix.to_path_buf());
+      }
+    }
+  }
+
+  let mut files = Vec::new();
+  walk(scripts, scripts, &mut files);
+
+  files
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn paths_are_found_whatever_their_case() {
+    let root = std::env::temp_dir().join(format!("cahirp-paths-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("Mods/modA/content/scripts/game")).unwrap();
+    std::fs::write(root.join("Mods/modA/content/scripts/game/R4Player.ws"), "").unwrap();
+
+    let found = find_case_insensitive(
+      &root,
+      Path::new("mods/moda/content/scripts/game/r4player.ws")
+    );
+    let exact = find_case_insensitive(&root, Path::new("Mods/modA"));
+    let missing = find_case_insensitive(&root, Path::new("mods/modB"));
+    let mods = mods_folder(&root);
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+      found,
+      Some(root.join("Mods/modA/content/scripts/game/R4Player.ws"))
+    );
+    assert_eq!(exact, Some(root.join("Mods/modA")));
+    assert_eq!(missing, None);
+    assert_eq!(mods, root.join("Mods"));
+  }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::encoding::read_file;
+
+/// Name of the file, in the documents folder of the game, where the mods are
+/// disabled or given a priority
+pub const MODS_SETTINGS_FILE: &str = "mods.settings";
+
+/// The load order of the mods as the game sees it. The mods are sorted by the
+/// name of their folder, except for the ones given a `Priority` in the
+/// `mods.settings` file which come first. In both cases the mod that comes
+/// first is the one whose files are loaded.
+#[derive(Debug, Default)]
+pub struct ModSettings {
+  /// The entries of the `mods.settings` file keyed by the lowercase name of
+  /// their mod, as the game ignores case
+  mods: HashMap<String, ModEntry>
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ModEntry {
+  enabled: bool,
+
+  /// A lower priority wins over a higher one
+  priority: Option<i32>
+}
+
+impl Default for ModEntry {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      priority: None
+    }
+  }
+}
+
+impl ModSettings {
+  /// Read the `mods.settings` file of the documents folder, the mods follow the
+  /// default order if there is none.
+  pub fn load(documents: &Path) -> Self {
+    let path = documents.join(MODS_SETTINGS_FILE);
+
+    match read_file(&path) {
+      Ok(contents) => Self::parse(&contents),
+      Err(e) => {
+        crate::cli::prints::mod_settings_not_read(&path, &e);
+
+        Self::default()
+      }
+    }
+  }
+
+  /// Parse the ini-like content of the file, where each mod has a section with
+  /// its `Enabled` and `Priority` values:
+  ///
+  /// ```ini
+  /// [modSomeMod]
+  /// Enabled=1
+  /// Priority=3
+  /// ```
+  pub fn parse(contents: &str) -> Self {
+    let mut mods = HashMap::new();
+    let mut current = None;
+
+    for line in contents.lines().map(str::trim) {
+      if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+        let name = section.trim().to_lowercase();
+        mods.entry(name.clone()).or_insert_with(ModEntry::default);
+        current = Some(name);
+
+        continue;
+      }
+
+      let (Some(name), Some((key, value))) = (&current, line.split_once('=')) else {
+        continue;
+      };
+
+      let Some(entry) = mods.get_mut(name) else {
+        continue;
+      };
+
+      match key.trim().to_lowercase().as_str() {
+        "enabled" => entry.enabled = value.trim() != "0",
+        "priority" => entry.priority = value.trim().parse().ok(),
+        _ => {}
+      }
+    }
+
+    Self { mods }
+  }
+
+  /// Whether the game loads the mod, a mod is disabled by a `~` in front of
+  /// its folder name or by `Enabled=0`
+  pub fn is_enabled(&self, name: &str) -> bool {
+    !name.starts_with('~') && self.entry(name).enabled
+  }
+
+  /// Sort the folders of the mods in the order the game loads them, the mod
+  /// whose files win comes first.
+  pub fn sort_by_priority(&self, folders: &mut [PathBuf], name: impl Fn(&Path) -> String) {
+    folders.sort_by_cached_key(|folder| {
+      let name = name(folder);
+      let priority = self.entry(&name).priority;
+
+      (priority.is_none(), priority, name.to_lowercase())
+    });
+  }
+
+  fn entry(&self, name: &str) -> ModEntry {
+    self
+      .mods
+      .get(&name.to_lowercase())
+      .copied()
+      .unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SETTINGS: &str = "[modDisabled]\nEnabled=0\nPriority=1\n\n[ModFirst]\nEnabled=1\nPriority=2\n\n[modSecond]\npriority = 10\n";
+
+  #[test]
+  fn enabled_mods() {
+    let settings = ModSettings::parse(SETTINGS);
+
+    assert!(!settings.is_enabled("modDisabled"));
+    assert!(settings.is_enabled("modfirst"));
+    assert!(settings.is_enabled("modUnlisted"));
+    assert!(!settings.is_enabled("~modUnlisted"));
+  }
+
+  #[test]
+  fn mods_with_a_priority_come_first() {
+    let settings = ModSettings::parse(SETTINGS);
+    let mut folders: Vec<PathBuf> = ["modA", "modSecond", "modB", "modFirst"]
+      .into_iter()
+      .map(PathBuf::from)
+      .collect();
+
+    settings.sort_by_priority(&mut folders, |folder| folder.to_string_lossy().into_owned());
+
+    assert_eq!(
+      folders,
+      ["modFirst", "modSecond", "modA", "modB"].map(PathBuf::from)
+    );
+  }
+
+  #[test]
+  fn values_outside_of_a_section_are_ignored() {
+    let settings = ModSettings::parse("Enabled=0\n[modA]\nEnabled=oops\nPriority=high\n");
+
+    assert!(settings.is_enabled("modA"));
+    assert_eq!(settings.entry("modA").priority, None);
+    assert_eq!(settings.mods.len(), 1);
+  }
+}
@@ -1,6 +1,4 @@
 #![feature(map_try_insert)]
-#![feature(extract_if)]
-#![feature(extend_one)]
 
 pub const VERBOSE: bool = cfg!(debug_assertions);
 
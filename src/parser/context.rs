@@ -7,7 +7,7 @@ impl Context {
     Self(Parameters::empty())
   }
 
-  pub fn parse_with_context<'a>(&'a mut self, i: &'a str) -> IResult<&str, Option<Directive>> {
+  pub fn parse_with_context<'a>(&'a mut self, i: &'a str) -> IResult<&'a str, Option<Directive>> {
     let (i, item) = DirectiveOrContext::parse(i)?;
 
     match item {
@@ -28,6 +28,11 @@ impl Context {
     Ok((i, Self(params)))
   }
 
+  /// Set where the parameters that were just added to the context were written
+  pub fn set_origin(&mut self, origin: Origin) {
+    self.0.set_origin(origin);
+  }
+
   /// Merge both Contexts into this one
   fn merge(&mut self, other: Self) {
     self.0.append(other.0)
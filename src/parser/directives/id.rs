@@ -1,22 +1,56 @@
 use std::fmt::Display;
 
-#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
-pub struct DirectiveId(usize);
+/// Identifies a directive across builds, it is derived from the mod and the
+/// recipe the directive comes from along with either the `id("...")` parameter
+/// of the directive or its parameters and code.
+#[derive(Debug, Clone, Copy, Default, Eq, Hash, PartialEq)]
+pub struct DirectiveId(u64);
 
 impl DirectiveId {
-  pub fn new(num: usize) -> Self {
-    Self(num)
-  }
-}
+  /// Derive the id from the parts that identify the directive, with a FNV-1a
+  /// hash as it is the same on every platform and every version of rust.
+  pub fn derive(parts: &[&str]) -> Self {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut hash = OFFSET_BASIS;
+    for part in parts {
+      // the parts are separated by a null byte so `["ab", "c"]` and `["a", "bc"]`
+      // don't give the same id
+      for byte in part.bytes().chain(std::iter::once(0)) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+      }
+    }
 
-impl Default for DirectiveId {
-  fn default() -> Self {
-    Self::new(0)
+    Self(hash)
   }
 }
 
 impl Display for DirectiveId {
+  /// Only the first 8 hexadecimal digits are displayed, like short git hashes
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}", self.0)
+    write!(f, "{:08x}", self.0 >> 32)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ids_are_the_same_on_every_build() {
+    assert_eq!(DirectiveId::derive(&[]).to_string(), "cbf29ce4");
+    assert_eq!(
+      DirectiveId::derive(&["modA", "@insert(x)"]).to_string(),
+      "e9c04e66"
+    );
+  }
+
+  #[test]
+  fn parts_are_separated() {
+    assert_eq!(DirectiveId::derive(&["ab", "c"]).to_string(), "ad22872f");
+    assert_eq!(DirectiveId::derive(&["a", "bc"]).to_string(), "401801fc");
+    assert_eq!(DirectiveId::derive(&["abc"]).to_string(), "fc17bb83");
   }
 }
@@ -1,7 +1,7 @@
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::codegen::CodeEmitter;
+use crate::codegen::{CodeEmitter, ExportDatabase, UseError};
 pub use crate::parser::prelude::*;
 
 mod insert;
@@ -14,6 +14,9 @@ pub use id::DirectiveId;
 pub struct Directive {
   pub id: DirectiveId,
 
+  /// Where the directive was written, its mod is used to namespace its exports
+  pub origin: Origin,
+
   pub insert: InsertDirective,
   pub code: String
 }
@@ -22,14 +25,15 @@ impl Directive {
   pub fn parse(i: &str) -> IResult<&str, Self> {
     let (i, _) = tag("@")(i)?;
     let (i, insert) = Self::parse_insert(i)?;
-    let code = i.trim().to_owned();
+    let code = trim_code(i).to_owned();
 
     Ok((
       "",
       Self {
         insert,
         code,
-        id: DirectiveId::default()
+        id: DirectiveId::default(),
+        origin: Origin::default()
       }
     ))
   }
@@ -46,11 +50,42 @@ impl Directive {
     self
   }
 
+  /// Replace the [Parameter::Use] parameters by the parameters of the exports
+  /// they refer to, resolved from the point of view of the directive's mod.
+  pub fn feed_exports(
+    &mut self, export_db: &ExportDatabase, warnings: &mut Vec<UseError>
+  ) -> Result<(), UseError> {
+    self
+      .insert
+      .parameters_mut()
+      .feed_exports(export_db, &self.origin.module, warnings)
+  }
+
   pub fn file_suffixes<'a>(&'a self) -> impl Iterator<Item = PathBuf> + 'a {
     self
       .parameters()
       .files()
-      .map(|suffix| PathBuf::from(suffix))
+      .filter(|&file| file != AUTO_FILE)
+      .map(PathBuf::from)
+  }
+
+  /// The position of the directive among all the recipes, directives are
+  /// collected and applied in that order for the builds to be reproducible.
+  pub fn source_order(&self) -> (&str, &Path, usize) {
+    (&self.origin.module, &self.origin.recipe, self.origin.line)
+  }
+
+  /// The parameters and the code of the directive with whitespace collapsed,
+  /// which identify a directive that isn't given an `id`.
+  pub fn content(&self) -> String {
+    let mut parts: Vec<String> = self.parameters().all().map(Parameter::to_string).collect();
+    parts.push(self.code.clone());
+
+    parts
+      .join(" ")
+      .split_whitespace()
+      .collect::<Vec<_>>()
+      .join(" ")
   }
 
   pub fn parameters(&self) -> &Parameters {
@@ -62,6 +97,27 @@ impl Directive {
   }
 }
 
+/// Remove the blank lines around the code while preserving the indentation of
+/// its first line, as it is needed to compute the relative indentation of the
+/// next lines, and the trailing spaces of its last line for inline code.
+fn trim_code(code: &str) -> &str {
+  let mut code = code.trim_end_matches(['\r', '\n']);
+  while let Some((rest, last_line)) = code.rsplit_once('\n') {
+    if !last_line.trim().is_empty() {
+      break;
+    }
+
+    code = rest.trim_end_matches(['\r', '\n']);
+  }
+
+  let leading = &code[..code.len() - code.trim_start().len()];
+
+  match leading.rfind('\n') {
+    Some(idx) => &code[idx + 1..],
+    None => code.trim_start_matches(['\r', '\n'])
+  }
+}
+
 impl Display for Directive {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     use owo_colors::OwoColorize;
@@ -4,8 +4,11 @@ pub use directives::*;
 mod context;
 pub use context::Context;
 
+mod origin;
+pub use origin::Origin;
+
 mod parameters;
-pub use parameters::{Parameter, Parameters};
+pub use parameters::{Parameter, Parameters, AUTO_FILE};
 
 pub mod prelude {
   pub use super::*;
@@ -0,0 +1,46 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+
+/// Where a directive, or a parameter, was written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Origin {
+  /// Name of the mod the recipe belongs to
+  pub module: String,
+
+  /// Path to the recipe file
+  pub recipe: PathBuf,
+
+  /// First line of the directive, or of the parameter, inside its recipe,
+  /// 1-based
+  pub line: usize,
+
+  /// Last line of the directive, or of the parameter, inside its recipe,
+  /// 1-based & inclusive
+  pub end_line: usize
+}
+
+impl Origin {
+  /// The name of the recipe file, without the folders that lead to it
+  pub fn recipe_name(&self) -> String {
+    self
+      .recipe
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned())
+      .unwrap_or_default()
+  }
+}
+
+impl Display for Origin {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.line == self.end_line {
+      true => write!(f, "{}:{}", self.recipe.display(), self.line),
+      false => write!(
+        f,
+        "{}:{}-{}",
+        self.recipe.display(),
+        self.line,
+        self.end_line
+      )
+    }
+  }
+}
@@ -1,41 +1,127 @@
+use std::fmt::Display;
 use std::ops::Deref;
+use std::sync::Arc;
 
-use crate::codegen::ExportDatabase;
+use crate::codegen::{
+  ExportDatabase, ExportKey, ExportLookupError, IndentMode, OnceMode, Stage, UseError,
+  MAX_USE_DEPTH
+};
 pub use crate::parser::prelude::*;
+use crate::parser::Origin;
 
+/// The `file(auto)` parameter, to locate the file from the anchors
+pub const AUTO_FILE: &str = "auto";
+
+/// The parameters of a directive, along with where each of them was written as
+/// they can come from an `@context` or from the export of another mod.
 #[derive(Debug, Clone)]
-pub struct Parameters(Vec<Parameter>);
+pub struct Parameters(Vec<Entry>);
+
+/// A parameter and where it was written, which is `None` until the recipe is
+/// done being parsed
+#[derive(Debug, Clone)]
+struct Entry {
+  parameter: Parameter,
+  origin: Option<Arc<Origin>>
+}
 
 impl Parameters {
-  pub fn feed_exports(&mut self, export_db: &ExportDatabase) {
-    let mut max = self.0.len();
-    let mut i = 0;
+  /// Expand every [Parameter::Use] with the parameters of the export it
+  /// refers to. The imported parameters are placed right after the `Use`
+  /// parameter and are themselves expanded from the point of view of the mod
+  /// that exported them.
+  ///
+  /// Cycles and chains deeper than [MAX_USE_DEPTH] stop the expansion, while
+  /// the `use` that couldn't be resolved are skipped and pushed to `warnings`
+  /// so the caller can decide how strict it wants to be.
+  pub fn feed_exports(
+    &mut self, export_db: &ExportDatabase, module: &str, warnings: &mut Vec<UseError>
+  ) -> Result<(), UseError> {
+    let params = self.clone();
+    let mut chain = Vec::new();
+
+    *self = Self::expand_uses(params, export_db, module, &mut chain, warnings)?;
+
+    Ok(())
+  }
+
+  fn expand_uses(
+    params: Parameters, export_db: &ExportDatabase, module: &str, chain: &mut Vec<ExportKey>,
+    warnings: &mut Vec<UseError>
+  ) -> Result<Parameters, UseError> {
+    let mut output = Parameters::empty();
+
+    for entry in params.0 {
+      let import = match &entry.parameter {
+        Parameter::Use(key) => match export_db.get(key, module) {
+          Ok(export) => Some(export),
+          Err(ExportLookupError::Ambiguous(candidates)) => {
+            warnings.push(UseError::Ambiguous {
+              module: module.to_owned(),
+              key: key.to_owned(),
+              candidates
+            });
+
+            None
+          }
+          Err(ExportLookupError::NotFound) => {
+            warnings.push(UseError::Unresolved {
+              module: module.to_owned(),
+              key: key.to_owned()
+            });
 
-    while i < max {
-      let param = &self.0[i];
+            None
+          }
+        },
+        _ => None
+      };
 
-      if let Parameter::Use(key) = param {
-        if let Some(params) = export_db.get(&key) {
-          let new_params = params.parameters().clone().into_inner();
+      output.0.push(entry);
 
-          let extension = new_params.len();
-          self.0.extend_reserve(extension);
-          max += extension;
+      if let Some((export_key, export)) = import {
+        let cycles = chain.contains(export_key);
+        chain.push(export_key.clone());
 
-          let mut inner_i = i + 1;
-          for new_param in new_params {
-            self.0.insert(inner_i, new_param);
-            inner_i += 1;
-          }
+        if cycles {
+          return Err(UseError::Cycle(chain.clone()));
+        }
+
+        if chain.len() > MAX_USE_DEPTH {
+          return Err(UseError::TooDeep(chain.clone()));
         }
+
+        // the imported parameters keep the origin they have in the export
+        let imported = export.parameters().clone();
+        output.append(Self::expand_uses(
+          imported,
+          export_db,
+          &export.origin.module,
+          chain,
+          warnings
+        )?);
+
+        chain.pop();
       }
+    }
+
+    Ok(output)
+  }
+
+  /// Set the origin of the parameters that don't have one yet
+  pub fn set_origin(&mut self, origin: Origin) {
+    let origin = Arc::new(origin);
 
-      i += 1;
+    for entry in self.0.iter_mut().filter(|entry| entry.origin.is_none()) {
+      entry.origin = Some(Arc::clone(&origin));
     }
   }
 
-  pub fn into_inner(self) -> Vec<Parameter> {
-    self.0
+  /// Iterate over the parameters along with where they were written
+  pub fn with_origins(&self) -> impl Iterator<Item = (&Parameter, Option<&Origin>)> {
+    self
+      .0
+      .iter()
+      .map(|entry| (&entry.parameter, entry.origin.as_deref()))
   }
 }
 
@@ -49,109 +135,186 @@ impl Parameters {
     let (i, params) = many0(Parameter::parse)(i)?;
     let (i, _) = trim(i)?;
 
-    Ok((i, Self(params)))
+    let entries = params
+      .into_iter()
+      .map(|parameter| Entry {
+        parameter,
+        origin: None
+      })
+      .collect();
+
+    Ok((i, Self(entries)))
   }
 
   pub fn append(&mut self, mut other: Parameters) {
     self.0.append(&mut other.0);
   }
 
-  pub fn all<'a>(&'a self) -> impl Iterator<Item = &'a Parameter> {
-    self.0.iter()
+  pub fn all(&self) -> impl DoubleEndedIterator<Item = &Parameter> {
+    self.0.iter().map(|entry| &entry.parameter)
   }
 
-  pub fn ats<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  pub fn ats(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::At(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn files<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  pub fn files(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::File(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn belows<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  /// Whether the file of the directive is located from its first anchor, which
+  /// is the case with `file(auto)` or without any `file` parameter
+  pub fn locates_file(&self) -> bool {
+    let mut files = self.files().peekable();
+
+    files.peek().is_none() || files.any(|file| file == AUTO_FILE)
+  }
+
+  /// The first pattern the cursor looks for, for a multiline selection it is
+  /// its first line
+  pub fn first_anchor(&self) -> Option<&str> {
+    self.all().find_map(|p| match p {
+      Parameter::At(s)
+      | Parameter::Below(s)
+      | Parameter::Above(s)
+      | Parameter::After(s)
+      | Parameter::Before(s)
+      | Parameter::Select(s) => Some(s.deref()),
+      Parameter::MultilineSelect(s) => s.lines().map(str::trim).find(|line| !line.is_empty()),
+      Parameter::UseConstructed(params) => params.first_anchor(),
+      _ => None
+    })
+  }
+
+  pub fn belows(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::Below(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn aboves<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  pub fn aboves(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::Above(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn notes<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  pub fn notes(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::Note(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn defines<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  pub fn defines(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::Define(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn ifdefs<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  pub fn ifdefs(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::IfDef(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn ifndefs<'a>(&'a self) -> impl Iterator<Item = &'a str> {
-    self.0.iter().filter_map(|p| match p {
+  pub fn ifndefs(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
       Parameter::IfNotDef(s) => Some(s.deref()),
       _ => None
     })
   }
 
+  pub fn uses(&self) -> impl Iterator<Item = &str> {
+    self.all().filter_map(|p| match p {
+      Parameter::Use(s) => Some(s.deref()),
+      _ => None
+    })
+  }
+
+  /// Get the name the author gave to the directive with `id("...")`, the first
+  /// one wins
+  pub fn id(&self) -> Option<&str> {
+    self.all().find_map(|p| match p {
+      Parameter::Id(s) => Some(s.deref()),
+      _ => None
+    })
+  }
+
   pub fn exports_first(&self) -> Option<&str> {
-    self.0.iter().find_map(|p| match p {
+    self.all().find_map(|p| match p {
       Parameter::Export(s) => Some(s.deref()),
       _ => None
     })
   }
 
-  pub fn has_ifndefs(&self) -> bool {
-    self.0.iter().any(|p| match p {
-      Parameter::IfNotDef(_) => true,
-      _ => false
+  /// Get the indentation mode of the emitted code, the last `indent` parameter
+  /// wins and [IndentMode::Auto] is used when there is none.
+  pub fn indent_mode(&self) -> IndentMode {
+    self
+      .all()
+      .rev()
+      .find_map(|p| match p {
+        Parameter::Indent(mode) => Some(*mode),
+        _ => None
+      })
+      .unwrap_or_default()
+  }
+
+  /// Get the [OnceMode] of the directive, the last `once` parameter wins
+  pub fn once_mode(&self) -> Option<OnceMode> {
+    self.all().rev().find_map(|p| match p {
+      Parameter::Once(mode) => Some(*mode),
+      _ => None
     })
   }
 
+  /// Get the [Stage] the directive runs in, the last `stage` parameter wins
+  pub fn stage(&self) -> Stage {
+    self
+      .all()
+      .rev()
+      .find_map(|p| match p {
+        Parameter::Stage(stage) => Some(*stage),
+        _ => None
+      })
+      .unwrap_or_default()
+  }
+
+  pub fn has_ifndefs(&self) -> bool {
+    self.all().any(|p| matches!(p, Parameter::IfNotDef(_)))
+  }
+
   pub fn has_ifndef_or_ifdef(&self) -> bool {
-    self.0.iter().any(|p| match p {
-      Parameter::IfNotDef(_) => true,
-      Parameter::IfDef(_) => true,
-      _ => false
-    })
+    self
+      .all()
+      .any(|p| matches!(p, Parameter::IfNotDef(_) | Parameter::IfDef(_)))
   }
 
   pub fn has_export(&self) -> bool {
-    self.0.iter().any(|p| match p {
-      Parameter::Export(_) => true,
-      _ => false
-    })
+    self.all().any(|p| matches!(p, Parameter::Export(_)))
   }
 }
 
 #[derive(Debug, Clone)]
 pub enum Parameter {
   /// Specifies one or many files to work on:
-  /// - if no File directive is found then all files in the `mods` directory.
+  /// - if no File directive is found, or with `file(auto)`, the file is the
+  ///   only script that contains the first anchor of the directive.
   /// - if one or more File directives are found, then these only the supplied
-  /// files will be used.
+  ///   files will be used.
+  ///
+  /// The file can be a pattern with `*`, `?` and `**` wildcards, expanded
+  /// against the script files by the [FilePool](crate::codegen::FilePool).
   File(String),
 
   /// Specifies an exact pattern to look for and where to place the directive
@@ -160,9 +323,9 @@ pub enum Parameter {
   /// Multiple At parameters can be used to progressively & precisely
   /// target a segment of the file. For example:
   /// - a first `At(class CInventoryComponent)` can be used to ensure we're
-  /// in the class we need
+  ///   in the class we need
   /// - a second `At(function EquipItem)` can be used to ensure we're in the
-  /// method we need
+  ///   method we need
   ///
   /// > The cursor controls where the provided code is emitted in the file.
   At(String),
@@ -173,9 +336,9 @@ pub enum Parameter {
   /// Multiple At parameters can be used to progressively & precisely
   /// target a segment of the file. For example:
   /// - a first `Below(class CInventoryComponent)` can be used to ensure we're
-  /// in the class we need
+  ///   in the class we need
   /// - a second `Below(function EquipItem)` can be used to ensure we're in the
-  /// method we need
+  ///   method we need
   ///
   /// > The cursor controls where the provided code is emitted in the file.
   Below(String),
@@ -186,13 +349,24 @@ pub enum Parameter {
   /// Multiple At parameters can be used to progressively & precisely
   /// target a segment of the file. For example:
   /// - a first `Above(class CInventoryComponent)` can be used to ensure we're
-  /// in the class we need
+  ///   in the class we need
   /// - a second `Above(function EquipItem)` can be used to ensure we're in the
-  /// method we need
+  ///   method we need
   ///
   /// > The cursor controls where the provided code is emitted in the file.
   Above(String),
 
+  /// Specifies an exact pattern to look for from the current position of the
+  /// cursor and to place the cursor right after it, inside the line.
+  ///
+  /// Once the cursor is inside a line the code is emitted inline, without any
+  /// newline nor indentation, which allows adding an argument to a call or a
+  /// condition to an `if` without replacing the whole line.
+  After(String),
+
+  /// Like [Parameter::After] but places the cursor right before the pattern.
+  Before(String),
+
   /// Specifies a pattern to select which should be replaced by the emitted
   /// code.
   Select(String),
@@ -222,7 +396,48 @@ pub enum Parameter {
   Export(String),
 
   Use(String),
-  UseConstructed(Parameters)
+  UseConstructed(Parameters),
+
+  /// Controls how the emitted code is indented, see [IndentMode] for the
+  /// available modes.
+  Indent(IndentMode),
+
+  /// Skips the emission if the code is already present in the file, see
+  /// [OnceMode] for where it is looked for.
+  Once(OnceMode),
+
+  /// The [Stage] the directive runs in, either a number or a named phase
+  Stage(Stage),
+
+  /// A name given by the author to the directive, which its id is derived from
+  /// rather than from its parameters and its code
+  Id(String)
+}
+
+impl Display for Parameter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Parameter::File(s) => write!(f, "file({s})"),
+      Parameter::At(s) => write!(f, "at({s})"),
+      Parameter::Below(s) => write!(f, "below({s})"),
+      Parameter::Above(s) => write!(f, "above({s})"),
+      Parameter::After(s) => write!(f, "after({s})"),
+      Parameter::Before(s) => write!(f, "before({s})"),
+      Parameter::Select(s) => write!(f, "select({s})"),
+      Parameter::MultilineSelect(s) => write!(f, "select[[{}]]", s.trim()),
+      Parameter::Note(s) => write!(f, "note({s})"),
+      Parameter::Define(s) => write!(f, "define({s})"),
+      Parameter::IfDef(s) => write!(f, "ifdef({s})"),
+      Parameter::IfNotDef(s) => write!(f, "ifndef({s})"),
+      Parameter::Export(s) => write!(f, "export({s})"),
+      Parameter::Use(s) => write!(f, "use({s})"),
+      Parameter::UseConstructed(_) => write!(f, "use(..)"),
+      Parameter::Indent(mode) => write!(f, "indent({})", mode.name()),
+      Parameter::Once(mode) => write!(f, "once({})", mode.name()),
+      Parameter::Stage(stage) => write!(f, "stage({stage})"),
+      Parameter::Id(s) => write!(f, "id(\"{s}\")")
+    }
+  }
 }
 
 impl Parameter {
@@ -233,6 +448,8 @@ impl Parameter {
       Self::parse_at,
       Self::parse_above,
       Self::parse_below,
+      Self::parse_after,
+      Self::parse_before,
       Self::parse_select,
       Self::parse_multiline_select,
       Self::parse_note,
@@ -240,7 +457,11 @@ impl Parameter {
       Self::parse_ifndef,
       Self::parse_define,
       Self::parse_export,
-      Self::parse_use
+      Self::parse_use,
+      Self::parse_indent,
+      Self::parse_once,
+      Self::parse_stage,
+      Self::parse_id
     ))(i)?;
     let (i, _) = trim(i)?;
 
@@ -271,6 +492,18 @@ impl Parameter {
     Ok((i, Self::Below(pattern)))
   }
 
+  fn parse_after(i: &str) -> IResult<&str, Self> {
+    let (i, pattern) = Self::parse_parameter("after", i)?;
+
+    Ok((i, Self::After(pattern)))
+  }
+
+  fn parse_before(i: &str) -> IResult<&str, Self> {
+    let (i, pattern) = Self::parse_parameter("before", i)?;
+
+    Ok((i, Self::Before(pattern)))
+  }
+
   fn parse_select(i: &str) -> IResult<&str, Self> {
     let (i, pattern) = Self::parse_parameter("select", i)?;
 
@@ -310,6 +543,48 @@ impl Parameter {
     Ok((i, Self::Use(pattern)))
   }
 
+  fn parse_indent(i: &str) -> IResult<&str, Self> {
+    let (rest, pattern) = Self::parse_parameter("indent", i)?;
+
+    match IndentMode::from_name(&pattern) {
+      Some(mode) => Ok((rest, Self::Indent(mode))),
+      None => Err(nom::Err::Failure(ParseError::from_error_kind(
+        i,
+        nom::error::ErrorKind::Verify
+      )))
+    }
+  }
+
+  fn parse_id(i: &str) -> IResult<&str, Self> {
+    let (i, pattern) = Self::parse_parameter("id", i)?;
+
+    Ok((i, Self::Id(pattern)))
+  }
+
+  fn parse_stage(i: &str) -> IResult<&str, Self> {
+    let (rest, pattern) = Self::parse_parameter("stage", i)?;
+
+    match Stage::from_name(&pattern) {
+      Some(stage) => Ok((rest, Self::Stage(stage))),
+      None => Err(nom::Err::Failure(ParseError::from_error_kind(
+        i,
+        nom::error::ErrorKind::Verify
+      )))
+    }
+  }
+
+  fn parse_once(i: &str) -> IResult<&str, Self> {
+    let (rest, pattern) = Self::parse_parameter("once", i)?;
+
+    match OnceMode::from_name(&pattern) {
+      Some(mode) => Ok((rest, Self::Once(mode))),
+      None => Err(nom::Err::Failure(ParseError::from_error_kind(
+        i,
+        nom::error::ErrorKind::Verify
+      )))
+    }
+  }
+
   fn parse_ifndef(i: &str) -> IResult<&str, Self> {
     let (i, pattern) = Self::parse_parameter("ifndef", i)?;
 
@@ -337,3 +612,33 @@ impl Parameter {
     Ok((i, pattern))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn params(i: &str) -> Parameters {
+    let (_, params) = Parameters::parse(i).unwrap();
+    params
+  }
+
+  #[test]
+  fn locates_file() {
+    assert!(params("at(class A)\n").locates_file());
+    assert!(params("file(auto)\nat(class A)\n").locates_file());
+    assert!(!params("file(game/player.ws)\nat(class A)\n").locates_file());
+  }
+
+  #[test]
+  fn first_anchor() {
+    assert_eq!(
+      params("file(auto)\nnote(x)\nbelow(var x)\nat(class A)\n").first_anchor(),
+      Some("var x")
+    );
+    assert_eq!(
+      params("select[[\n\n\t\tfoo();\n\t\tbar();\n]]\n").first_anchor(),
+      Some("foo();")
+    );
+    assert_eq!(params("file(auto)\n").first_anchor(), None);
+  }
+}